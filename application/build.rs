@@ -14,8 +14,14 @@ fn main() {
         Err(_) => "unknown".to_string(),
     };
 
+    let hw_revision = match env::var("HW_REVISION") {
+        Ok(v) => v,
+        Err(_) => "1".to_string(),
+    };
+
     println!("cargo:rustc-env=VERSION={}", version);
     println!("cargo:rustc-env=GIT_REF={}", git_version);
+    println!("cargo:rustc-env=HW_REVISION={}", hw_revision);
 
     // Put the linker script somewhere the linker can find it
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
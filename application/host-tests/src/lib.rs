@@ -0,0 +1,52 @@
+// Unit tests of the firmware modules that don't touch the hardware. The firmware only builds
+// for the MCU, so those modules are built again here for the host, where cargo can run the
+// #[cfg(test)] blocks they hold:
+//
+//   cd host-tests && cargo test
+#![allow(dead_code)]
+
+#[path = "../../src/clock.rs"]
+mod clock;
+#[path = "../../src/console.rs"]
+mod console;
+#[path = "../../src/ctlpins.rs"]
+mod ctlpins;
+#[path = "../../src/events.rs"]
+mod events;
+// older firmware code, not held to clippy
+#[allow(clippy::needless_return)]
+#[path = "../../src/filter.rs"]
+mod filter;
+#[path = "../../src/json.rs"]
+mod json;
+#[path = "../../src/lease.rs"]
+mod lease;
+#[path = "../../src/msos.rs"]
+mod msos;
+#[path = "../../src/powermeter.rs"]
+mod powermeter;
+#[path = "../../src/reply.rs"]
+mod reply;
+#[path = "../../src/script.rs"]
+mod script;
+#[path = "../../src/sequence.rs"]
+mod sequence;
+#[path = "../../src/stream.rs"]
+mod stream;
+#[path = "../../src/tokenizer.rs"]
+mod tokenizer;
+
+// config.rs needs the flash driver, this is the helper of it used by the modules above
+mod config {
+    pub fn value_bytes(field: &[u8]) -> &[u8] {
+        match field.iter().position(|c| *c == 0) {
+            Some(l) => &field[..l],
+            None => field,
+        }
+    }
+}
+
+// uptime of the RTIC monotonic in the firmware, see main.rs
+pub fn uptime_ms() -> u64 {
+    0
+}
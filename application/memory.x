@@ -2,6 +2,7 @@
 MEMORY
 {
     BOOTLOADER : ORIGIN = 0x08000000, LENGTH = 32K
+    PROFILE_FLASH : ORIGIN = 0x08008000, LENGTH = 16K
     DATA_FLASH : ORIGIN = 0x0800C000, LENGTH = 16K
     FLASH : ORIGIN = 0x08010000, LENGTH = 512K - 0x10000
     RAM : ORIGIN = 0x20000010, LENGTH = 128K - 0x10
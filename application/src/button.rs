@@ -0,0 +1,83 @@
+use embedded_hal::digital::InputPin;
+
+// The bootloader enters DFU mode when the button is held during reset, so the
+// application gestures start with a press right after power-up, once the
+// application is running, and only within the first GESTURE_WINDOW_MS.
+//
+//   factory reset: press within the window and hold for FACTORY_RESET_HOLD_MS,
+//                  the LEDs blink while holding and stay lit once the config is erased
+
+const GESTURE_WINDOW_MS: u32 = 3_000;
+const FACTORY_RESET_HOLD_MS: u32 = 5_000;
+const BLINK_PERIOD_MS: u32 = 250;
+const DONE_FEEDBACK_MS: u32 = 2_000;
+
+pub enum ButtonEvent {
+    None,
+    Pressed,
+    FactoryReset,
+}
+
+pub struct Button<P>
+where
+    P: InputPin,
+{
+    pin: P,
+    uptime_ms: u32,
+    held_ms: u32,
+    gesture: bool,  // the current press started within the gesture window
+    done_ms: u32,   // remaining time for the "done" LED feedback
+}
+
+impl<P> Button<P>
+where
+    P: InputPin,
+{
+    pub fn new(pin: P) -> Self {
+        Self { pin, uptime_ms: 0, held_ms: 0, gesture: false, done_ms: 0 }
+    }
+
+    pub fn is_pressed(&mut self) -> bool {
+        // the button pulls the input low when pressed
+        self.pin.is_low().unwrap_or(false)
+    }
+
+    // must be called periodically with the elapsed time since the last call
+    pub fn tick(&mut self, elapsed_ms: u32) -> ButtonEvent {
+        self.uptime_ms = self.uptime_ms.saturating_add(elapsed_ms);
+        self.done_ms = self.done_ms.saturating_sub(elapsed_ms);
+
+        if !self.is_pressed() {
+            self.held_ms = 0;
+            self.gesture = false;
+            return ButtonEvent::None;
+        }
+
+        let pressed = self.held_ms == 0;
+        if pressed && self.uptime_ms <= GESTURE_WINDOW_MS {
+            self.gesture = true;
+        }
+
+        let was_held = self.held_ms;
+        self.held_ms = self.held_ms.saturating_add(elapsed_ms);
+
+        if self.gesture && was_held < FACTORY_RESET_HOLD_MS && self.held_ms >= FACTORY_RESET_HOLD_MS {
+            self.gesture = false;
+            self.done_ms = DONE_FEEDBACK_MS;
+            return ButtonEvent::FactoryReset;
+        }
+
+        if pressed {
+            return ButtonEvent::Pressed;
+        }
+        ButtonEvent::None
+    }
+
+    // true when the LEDs should be lit to give feedback about a gesture in progress
+    pub fn feedback(&self) -> bool {
+        if self.done_ms > 0 {
+            return true;
+        }
+        self.gesture && (self.held_ms / BLINK_PERIOD_MS) % 2 == 0
+    }
+}
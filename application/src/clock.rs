@@ -0,0 +1,238 @@
+use core::cell::Cell;
+
+use cortex_m::interrupt::Mutex;
+
+// Wall clock set by the host, so events, console lines and power samples can be correlated
+// with CI logs. The time is kept from the RTIC monotonic: the host sets the epoch time once,
+// and later resyncs let the firmware estimate the drift of the crystal against the host
+// clock, which is then applied between syncs.
+//
+// All the times are in ms since the Unix epoch. Events, console lines and sample packets
+// carry a Timestamp taken when they happen: the epoch time once the host set it, the uptime
+// until then, with a flag telling which one it is.
+
+pub const MAX_DRIFT_PPM: i32 = 1000;
+// drift estimates over shorter intervals would be dominated by the USB latency
+const MIN_DRIFT_INTERVAL_MS: u64 = 60_000;
+
+#[derive(Clone, Copy)]
+struct Sync {
+    epoch_ms: u64,      // time set by the host
+    uptime_ms: u64,     // uptime when it was set
+    drift_ppm: i32,     // applied to the time elapsed since then
+    correction_ms: i32, // step applied by the last sync, positive when the clock was late
+}
+
+#[derive(Clone, Copy)]
+pub enum TimeError {
+    NotSet,   // drift estimation needs a previous sync
+    TooSoon,  // less than MIN_DRIFT_INTERVAL_MS since the previous sync
+}
+
+static CLOCK: Mutex<Cell<Option<Sync>>> = Mutex::new(Cell::new(None));
+
+// the host tests have no interrupts to mask, and don't set the clock
+#[cfg(target_os = "none")]
+use cortex_m::interrupt::free;
+#[cfg(not(target_os = "none"))]
+fn free<R>(f: impl FnOnce(&cortex_m::interrupt::CriticalSection) -> R) -> R {
+    f(unsafe { &cortex_m::interrupt::CriticalSection::new() })
+}
+
+fn get() -> Option<Sync> {
+    free(|cs| CLOCK.borrow(cs).get())
+}
+
+fn put(sync: Sync) {
+    free(|cs| CLOCK.borrow(cs).set(Some(sync)));
+}
+
+fn time_at(sync: &Sync, uptime_ms: u64) -> u64 {
+    let elapsed = uptime_ms.saturating_sub(sync.uptime_ms) as i64;
+    let corrected = elapsed + elapsed * sync.drift_ppm as i64 / 1_000_000;
+    (sync.epoch_ms as i64 + corrected) as u64
+}
+
+// timestamp of events, console lines and sample packets, TIMESTAMP_SIZE bytes little endian:
+//   0 u8  1 when ms is the epoch time, 0 when it is the uptime as the time isn't set
+//   1 u64 ms
+pub const TIMESTAMP_SIZE: usize = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamp {
+    pub wall: bool,
+    pub ms: u64,
+}
+
+impl Timestamp {
+    pub fn to_bytes(self) -> [u8; TIMESTAMP_SIZE] {
+        let mut buf = [0; TIMESTAMP_SIZE];
+        buf[0] = self.wall as u8;
+        buf[1..].copy_from_slice(&self.ms.to_le_bytes());
+        buf
+    }
+}
+
+pub fn timestamp() -> Timestamp {
+    let now = crate::uptime_ms();
+    match get() {
+        Some(sync) => Timestamp { wall: true, ms: time_at(&sync, now) },
+        None => Timestamp { wall: false, ms: now },
+    }
+}
+
+// epoch time in ms, None until the host sets it
+pub fn wall_ms() -> Option<u64> {
+    get().map(|sync| time_at(&sync, crate::uptime_ms()))
+}
+
+pub fn drift_ppm() -> i32 {
+    get().map_or(0, |sync| sync.drift_ppm)
+}
+
+// step to the host time, returns the correction applied in ms
+pub fn set(epoch_ms: u64) -> i32 {
+    let now = crate::uptime_ms();
+    let previous = get();
+    let correction = previous.map_or(0, |sync| clamp_i32(epoch_ms as i64 - time_at(&sync, now) as i64));
+    let drift_ppm = previous.map_or(0, |sync| sync.drift_ppm);
+    put(Sync { epoch_ms, uptime_ms: now, drift_ppm, correction_ms: correction });
+    correction
+}
+
+// step to the host time and correct the drift with the error accumulated since
+// the previous sync, returns the correction applied in ms
+pub fn sync(epoch_ms: u64) -> Result<i32, TimeError> {
+    let now = crate::uptime_ms();
+    let previous = get().ok_or(TimeError::NotSet)?;
+    let elapsed = now.saturating_sub(previous.uptime_ms);
+    if elapsed < MIN_DRIFT_INTERVAL_MS {
+        return Err(TimeError::TooSoon);
+    }
+    let error = epoch_ms as i64 - time_at(&previous, now) as i64;
+    let drift = previous.drift_ppm as i64 + error * 1_000_000 / elapsed as i64;
+    let drift_ppm = drift.clamp(-MAX_DRIFT_PPM as i64, MAX_DRIFT_PPM as i64) as i32;
+    let correction = clamp_i32(error);
+    put(Sync { epoch_ms, uptime_ms: now, drift_ppm, correction_ms: correction });
+    Ok(correction)
+}
+
+// set the drift explicitly, i.e. a value measured by the host over a long run
+pub fn set_drift(ppm: i32) -> Result<(), TimeError> {
+    let now = crate::uptime_ms();
+    let mut sync = get().ok_or(TimeError::NotSet)?;
+    // restart from the current time so the new drift only applies from now on
+    sync.epoch_ms = time_at(&sync, now);
+    sync.uptime_ms = now;
+    sync.drift_ppm = ppm.clamp(-MAX_DRIFT_PPM, MAX_DRIFT_PPM);
+    put(sync);
+    Ok(())
+}
+
+// clock state for the control interface, all values little endian:
+//   0  u8  1 when the host set the time, 0 otherwise
+//   1  u64 epoch time in ms, 0 when not set
+//   9  u64 uptime in ms
+//   17 i32 drift correction in ppm
+//   21 i32 correction applied by the last set or sync in ms
+pub fn status() -> heapless::Vec<u8, 25> {
+    let now = crate::uptime_ms();
+    let sync = get();
+    let mut buf = heapless::Vec::<u8, 25>::new();
+    buf.push(sync.is_some() as u8).ok();
+    buf.extend_from_slice(&sync.map_or(0, |s| time_at(&s, now)).to_le_bytes()).ok();
+    buf.extend_from_slice(&now.to_le_bytes()).ok();
+    buf.extend_from_slice(&sync.map_or(0, |s| s.drift_ppm).to_le_bytes()).ok();
+    buf.extend_from_slice(&sync.map_or(0, |s| s.correction_ms).to_le_bytes()).ok();
+    buf
+}
+
+fn clamp_i32(v: i64) -> i32 {
+    v.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+// civil date of an epoch time in ms: year, month, day, hour, minute, second, ms
+pub fn to_utc(epoch_ms: u64) -> (u32, u32, u32, u32, u32, u32, u32) {
+    let ms = (epoch_ms % 1000) as u32;
+    let secs = epoch_ms / 1000;
+    let (days, rem) = (secs / 86400, (secs % 86400) as u32);
+
+    // days to civil date, from Howard Hinnant's date algorithms
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = (yoe + era * 400) as u32 + (month <= 2) as u32;
+
+    (year, month, day, rem / 3600, rem / 60 % 60, rem % 60, ms)
+}
+
+// epoch time given as seconds with an optional fraction, i.e. the output of date +%s.%N
+pub fn parse_epoch_ms(s: &str) -> Option<u64> {
+    let (secs, frac) = match s.find('.') {
+        Some(p) => (&s[..p], &s[p + 1..]),
+        None => (s, ""),
+    };
+    if !frac.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut ms: u64 = 0;
+    for i in 0..3 {
+        ms = ms * 10 + frac.as_bytes().get(i).map_or(0, |c| (c - b'0') as u64);
+    }
+    secs.parse::<u64>().ok()?.checked_mul(1000)?.checked_add(ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_seconds() {
+        assert_eq!(parse_epoch_ms("0"), Some(0));
+        assert_eq!(parse_epoch_ms("1700000000"), Some(1_700_000_000_000));
+        assert_eq!(parse_epoch_ms("1700000000."), Some(1_700_000_000_000));
+    }
+
+    #[test]
+    fn epoch_fraction() {
+        assert_eq!(parse_epoch_ms("1700000000.5"), Some(1_700_000_000_500));
+        assert_eq!(parse_epoch_ms("1700000000.05"), Some(1_700_000_000_050));
+        assert_eq!(parse_epoch_ms("1700000000.123"), Some(1_700_000_000_123));
+        // date +%s.%N, the digits past the ms are dropped
+        assert_eq!(parse_epoch_ms("1700000000.123999999"), Some(1_700_000_000_123));
+    }
+
+    #[test]
+    fn invalid_epoch() {
+        assert_eq!(parse_epoch_ms(""), None);
+        assert_eq!(parse_epoch_ms(".5"), None);
+        assert_eq!(parse_epoch_ms("-1"), None);
+        assert_eq!(parse_epoch_ms("1700000000.5s"), None);
+        assert_eq!(parse_epoch_ms("1700000000.-5"), None);
+        assert_eq!(parse_epoch_ms("17e8"), None);
+        assert_eq!(parse_epoch_ms("18446744073709552"), None); // overflows in ms
+    }
+
+    #[test]
+    fn timestamp_encoding() {
+        let t = Timestamp { wall: true, ms: 1_700_000_000_123 };
+        assert_eq!(t.to_bytes(), [1, 0x7b, 0x68, 0xe5, 0xcf, 0x8b, 0x01, 0, 0]);
+        let t = Timestamp { wall: false, ms: 42 };
+        assert_eq!(t.to_bytes(), [0, 42, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(to_utc(0), (1970, 1, 1, 0, 0, 0, 0));
+        assert_eq!(to_utc(1_700_000_000_123), (2023, 11, 14, 22, 13, 20, 123));
+        assert_eq!(to_utc(951_782_400_000), (2000, 2, 29, 0, 0, 0, 0)); // leap day of a leap century
+        assert_eq!(to_utc(4_107_542_399_999), (2100, 2, 28, 23, 59, 59, 999)); // 2100 is no leap year
+        assert_eq!(to_utc(4_107_542_400_000), (2100, 3, 1, 0, 0, 0, 0));
+        assert_eq!(to_utc(1_735_689_599_000), (2024, 12, 31, 23, 59, 59, 0));
+    }
+}
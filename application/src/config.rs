@@ -1,7 +1,10 @@
-use core::{mem::size_of, cmp::min};
+use core::mem::size_of;
 
 use stm32f4xx_hal::flash::{LockedFlash, FlashExt};
 
+use crate::ctlpins::PinState;
+use crate::profiles::{ProfileArea, ProfileBlock};
+
 // Configuration is stored in the 3'rd sector of the flash memory, starting at 0x0800_C000.
 // The sector is 16k, so we can store 16 ConfigBlocks of 1k each. The last one with 
 // the magic word is the valid one.
@@ -9,9 +12,53 @@ use stm32f4xx_hal::flash::{LockedFlash, FlashExt};
 // and only erase the sector when all blocks are used.
 
 const FLASH_SECTOR : u8 = 3;
+const CONFIG_SLOTS : usize = 16;
 const FLASH_BASE : usize = 0x0800_0000;
 const FLASH_CONFIG_BASE : usize = 0x0800_C000; // see memory.x
 
+// The STM32F411 flash sectors are specified for 10k erase cycles, warn well before that
+pub const ERASE_WARNING_THRESHOLD : u32 = 8_000;
+
+pub const LOCK_SECRET_LENGTH : usize = 16;
+
+// Confirmation token required to wipe the config from the shell or the control interface
+pub const FACTORY_RESET_TOKEN : &str = "erase-config";
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigError {
+    Erase = 1,   // the config sector could not be erased
+    Program = 2, // the flash controller reported an error while programming
+    Verify = 3,  // the data read back from flash does not match what was written
+    TooLong = 4, // the value does not fit in the config field
+    Locked = 5,  // the config is locked and no unlock secret was supplied
+    BadSecret = 6, // the supplied unlock secret does not match, or is not a valid secret
+    NotFound = 7,  // there is no profile with the given name or id
+    NoSpace = 8,   // all the profiles are in use
+    InvalidJson = 9, // the json blob is not valid JSON
+}
+
+impl ConfigError {
+    // status code reported over the control interface, 0 is used for success
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigError::Erase => "flash erase failed",
+            ConfigError::Program => "flash program failed",
+            ConfigError::Verify => "flash verification failed",
+            ConfigError::TooLong => "value too long",
+            ConfigError::Locked => "config is locked",
+            ConfigError::BadSecret => "wrong unlock secret",
+            ConfigError::NotFound => "profile not found",
+            ConfigError::NoSpace => "no free profile",
+            ConfigError::InvalidJson => "invalid json",
+        }
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Debug)]
 pub struct ConfigBlock {
@@ -24,7 +71,12 @@ pub struct ConfigBlock {
     pub power_off: [u8; 32], // power_off method i.e. "bL,w11,bZ"
     pub power_rescue: [u8; 32], // power_off method i.e. "aL,rL,w1,rZ,w1,aZ"
     pub json : [u8; 512], // json blob config
-    padding: [u8; 1024-64-256-64-4-32-32-32-512], // padding to make up for 1024 byte blocks
+    erase_count: u32,     // times the config sector has been erased, carried over on every write
+    write_count: u32,     // config blocks written to the sector, carried over on every write
+    locked: u8,           // writes require lock_secret when not 0, carried over on every write
+    lock_secret: [u8; LOCK_SECRET_LENGTH],
+    active_profile: u8,   // id+1 of the last loaded profile, 0 for none
+    padding: [u8; 1024-64-256-64-4-32-32-32-512-4-4-1-LOCK_SECRET_LENGTH-1], // padding to make up for 1024 byte blocks
     magic: u32,           // magic word to know if this flash config block is valid
 
 }
@@ -39,8 +91,13 @@ impl ConfigBlock {
             power_off: [0; 32],
             power_rescue: [0; 32],
             json : [0; 512], // json blob config
+            erase_count: 0,
+            write_count: 0,
+            locked: 0,
+            lock_secret: [0; LOCK_SECRET_LENGTH],
+            active_profile: 0,
             magic: MAGIC,
-            padding: [0; 1024-64-256-64-4-32-32-32-512],
+            padding: [0; 1024-64-256-64-4-32-32-32-512-4-4-1-LOCK_SECRET_LENGTH-1],
         }
     }
 
@@ -52,78 +109,136 @@ impl ConfigBlock {
         self.magic != MAGIC && self.magic != 0xFFFF_FFFF
     }
 
-    pub fn set_name(mut self,name: &[u8]) -> Self {
-        let l = min(name.len(), self.name.len());
-        self.name[..l].copy_from_slice(&name[..l]);
-        self.name[l..].fill(0);
-        self
+    pub fn is_locked(&self) -> bool {
+        self.locked != 0
     }
 
-    pub fn set_tags(mut self, tags: &[u8]) -> Self {
-        let l = min(tags.len(), self.tags.len());
-        self.tags[..l].copy_from_slice(&tags[..l]);
-        self.tags[l..].fill(0);
-        self
+    pub fn active_profile(&self) -> Option<u8> {
+        self.active_profile.checked_sub(1)
     }
 
-    pub fn set_json(mut self, json: &[u8]) -> Self {
-        let l = min(json.len(), self.json.len());
-        self.json[..l].copy_from_slice(&json[..l]);
-        self.json[l..].fill(0);
+    pub fn set_active_profile(mut self, id: Option<u8>) -> Self {
+        self.active_profile = id.map_or(0, |id| id + 1);
         self
     }
 
-    pub fn set_usb_console(mut self, usb_console: &[u8]) -> Self {
-        let l = min(usb_console.len(), self.usb_console.len());
-        self.usb_console[..l].copy_from_slice(&usb_console[..l]);
-        self.usb_console[l..].fill(0);
-        self
+    pub fn set_name(mut self, name: &[u8]) -> Result<Self, ConfigError> {
+        set_field(&mut self.name, name)?;
+        Ok(self)
     }
 
-    pub fn set_power_on(mut self, power_on: &[u8]) -> Self {
-        let l = min(power_on.len(), self.power_on.len());
-        self.power_on[..l].copy_from_slice(&power_on[..l]);
-        self.power_on[l..].fill(0);
-        self
+    pub fn set_tags(mut self, tags: &[u8]) -> Result<Self, ConfigError> {
+        set_field(&mut self.tags, tags)?;
+        Ok(self)
     }
-    pub fn set_power_off(mut self, power_off: &[u8]) -> Self {
-        let l = min(power_off.len(), self.power_off.len());
-        self.power_off[..l].copy_from_slice(&power_off[..l]);
-        self.power_off[l..].fill(0);
-        self
+
+    // an empty value clears the json, anything else must be valid JSON
+    pub fn set_json(mut self, json: &[u8]) -> Result<Self, ConfigError> {
+        if !json.is_empty() && crate::json::validate(json).is_err() {
+            return Err(ConfigError::InvalidJson);
+        }
+        set_field(&mut self.json, json)?;
+        Ok(self)
     }
 
-    pub fn set_power_rescue(mut self, power_rescue: &[u8]) -> Self {
-        let l = min(power_rescue.len(), self.power_rescue.len());
-        self.power_rescue[..l].copy_from_slice(&power_rescue[..l]);
-        self.power_rescue[l..].fill(0);
-        self
+    pub fn set_usb_console(mut self, usb_console: &[u8]) -> Result<Self, ConfigError> {
+        set_field(&mut self.usb_console, usb_console)?;
+        Ok(self)
+    }
+
+    pub fn set_power_on(mut self, power_on: &[u8]) -> Result<Self, ConfigError> {
+        set_field(&mut self.power_on, power_on)?;
+        Ok(self)
+    }
+
+    pub fn set_power_off(mut self, power_off: &[u8]) -> Result<Self, ConfigError> {
+        set_field(&mut self.power_off, power_off)?;
+        Ok(self)
+    }
+
+    pub fn set_power_rescue(mut self, power_rescue: &[u8]) -> Result<Self, ConfigError> {
+        set_field(&mut self.power_rescue, power_rescue)?;
+        Ok(self)
+    }
+
+}
+
+fn set_field(field: &mut [u8], value: &[u8]) -> Result<(), ConfigError> {
+    if value.len() > field.len() {
+        return Err(ConfigError::TooLong);
     }
+    field[..value.len()].copy_from_slice(value);
+    field[value.len()..].fill(0);
+    Ok(())
+}
 
+// compares every byte whatever the result, so the time taken doesn't tell how much of the
+// secret matched. Secrets never contain NUL, which pads the stored one
+fn secret_matches(secret: &[u8], lock_secret: &[u8; LOCK_SECRET_LENGTH]) -> bool {
+    if secret.len() > LOCK_SECRET_LENGTH || secret.contains(&0) {
+        return false;
+    }
+    let mut padded = [0; LOCK_SECRET_LENGTH];
+    padded[..secret.len()].copy_from_slice(secret);
+    padded.iter().zip(lock_secret.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
 }
 
 const MAGIC: u32 = 0x601dbeef;
 
+// config values are stored NUL padded, return the value without the padding
+pub fn value_bytes(field: &[u8]) -> &[u8] {
+    match field.iter().position(|c| *c == 0) {
+        Some(l) => &field[..l],
+        None => field,
+    }
+}
+
 // The flash area in 0x0800_C000 - 0x0800_FFFF is reserved for the config block.
 #[repr(C, packed)]
 struct ConfigAreaFlash {
-    config: [ConfigBlock; 16],
+    config: [ConfigBlock; CONFIG_SLOTS],
     // DO NOT ADD MORE VARIABLES HERE
 }
 
+#[derive(Clone, Copy)]
+pub struct ConfigStats {
+    pub erase_count: u32,
+    pub write_count: u32,
+    pub profile_erase_count: u32, // of the profile sector, see profiles.rs
+    pub active_slot: Option<usize>, // index of the config block in use, None when the sector is blank
+    pub slots: usize,
+}
+
+impl ConfigStats {
+    pub fn new() -> Self {
+        ConfigStats { erase_count: 0, write_count: 0, profile_erase_count: 0, active_slot: None, slots: CONFIG_SLOTS }
+    }
+
+    // either sector is wearing out
+    pub fn wear_warning(&self) -> bool {
+        self.erase_count.max(self.profile_erase_count) >= ERASE_WARNING_THRESHOLD
+    }
+}
+
 pub struct ConfigArea {
     flash_config: &'static ConfigAreaFlash,
+    profiles: ProfileArea,
     flash: LockedFlash,
+    generation: u32, // bumped on every change to the config sector
 }
 
 impl ConfigArea {
-    pub fn new(flash: LockedFlash) -> Self {
+    pub fn new(mut flash: LockedFlash) -> Self {
+        let profiles = ProfileArea::new(&mut flash);
         let mut cfg = ConfigArea {
             flash_config: ConfigAreaFlash::new(),
+            profiles: profiles,
             flash: flash,
+            generation: 0,
         };
         if cfg.flash_config.format_error() {
-            cfg.erase_flash();
+            // nobody to report to at boot, a failing sector will show up on the next write
+            cfg.erase_flash().ok();
         }
         cfg
     }
@@ -132,11 +247,141 @@ impl ConfigArea {
         self.flash_config.ram_config()
     }
 
-    fn erase_flash(&mut self) {
-        let mut unlocked_flash = self.flash.unlocked();
-        unlocked_flash.erase(FLASH_SECTOR).unwrap();
+    // changes every time the config is written or erased, so readers can cache get()
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn stats(&self) -> ConfigStats {
+        let cfg = self.get();
+        ConfigStats {
+            erase_count: cfg.erase_count,
+            write_count: cfg.write_count,
+            profile_erase_count: self.profiles.erase_count(),
+            active_slot: self.flash_config.get_current(),
+            slots: CONFIG_SLOTS,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.get().is_locked()
     }
-    pub fn write_config(&mut self, cfg: &ConfigBlock) -> Result<(),()> {
+
+    // check that a write is allowed, a secret is only needed while the config is locked
+    fn authorize(&self, secret: Option<&[u8]>) -> Result<(), ConfigError> {
+        let cfg = self.get();
+        if !cfg.is_locked() {
+            return Ok(());
+        }
+        match secret {
+            Some(s) if secret_matches(s, &cfg.lock_secret) => Ok(()),
+            Some(_) => Err(ConfigError::BadSecret),
+            None => Err(ConfigError::Locked),
+        }
+    }
+
+    // lock the config, further writes need the secret until unlocked
+    pub fn lock(&mut self, secret: &[u8]) -> Result<(), ConfigError> {
+        if secret.is_empty() || secret.len() > LOCK_SECRET_LENGTH || secret.contains(&0) {
+            return Err(ConfigError::BadSecret);
+        }
+        self.authorize(None)?;
+        let cfg = self.get();
+        let mut lock_secret = [0; LOCK_SECRET_LENGTH];
+        lock_secret[..secret.len()].copy_from_slice(secret);
+        self.store(&cfg, 1, lock_secret)
+    }
+
+    pub fn unlock(&mut self, secret: &[u8]) -> Result<(), ConfigError> {
+        self.authorize(Some(secret))?;
+        let cfg = self.get();
+        self.store(&cfg, 0, [0; LOCK_SECRET_LENGTH])
+    }
+
+    pub fn profiles(&self) -> &ProfileArea {
+        &self.profiles
+    }
+
+    pub fn active_profile(&self) -> Option<&ProfileBlock> {
+        self.get().active_profile().and_then(|id| self.profiles.get(id))
+    }
+
+    // store the DUT settings of the current config and the pin states as a profile, returns its id
+    pub fn save_profile(&mut self, name: &[u8], pins: &[PinState; 5], secret: Option<&[u8]>) -> Result<u8, ConfigError> {
+        self.authorize(secret)?;
+        let cfg = self.get();
+        self.profiles.save(&mut self.flash, name, &cfg, pins)
+    }
+
+    // write the profile settings to the config and make it the active one,
+    // returns the pin states to apply
+    pub fn load_profile(&mut self, id: u8, secret: Option<&[u8]>) -> Result<[PinState; 5], ConfigError> {
+        let profile = self.profiles.get(id).ok_or(ConfigError::NotFound)?;
+        let pins = profile.pins();
+        let cfg = profile.to_config(self.get()).set_active_profile(Some(id));
+        self.write_config_with_secret(&cfg, secret)?;
+        Ok(pins)
+    }
+
+    // a deleted active profile is no longer active, as its id goes to the next profile saved
+    pub fn delete_profile(&mut self, name: &[u8], secret: Option<&[u8]>) -> Result<(), ConfigError> {
+        self.authorize(secret)?;
+        let id = self.profiles.delete(&mut self.flash, name)?;
+        let cfg = self.get();
+        if cfg.active_profile() == Some(id) {
+            self.write_config_with_secret(&cfg.set_active_profile(None), secret)?;
+        }
+        Ok(())
+    }
+
+    // the boot script run at startup, see script.rs
+    pub fn autoexec(&self) -> &[u8] {
+        self.profiles.autoexec()
+    }
+
+    pub fn set_autoexec(&mut self, script: &[u8], secret: Option<&[u8]>) -> Result<(), ConfigError> {
+        self.authorize(secret)?;
+        self.profiles.set_autoexec(&mut self.flash, script)
+    }
+
+    // erase the config and profile sectors, get() returns the default config afterwards
+    pub fn factory_reset(&mut self, secret: Option<&[u8]>) -> Result<(), ConfigError> {
+        self.authorize(secret)?;
+        self.force_factory_reset()
+    }
+
+    // factory reset ignoring the lock, only for requests with physical access to the device
+    pub fn force_factory_reset(&mut self) -> Result<(), ConfigError> {
+        let stats = self.stats();
+        self.profiles.erase(&mut self.flash)?;
+        self.erase_flash()?;
+        // the wear statistics describe the sector, not the config, so they survive the reset
+        let mut cfg = ConfigBlock::new();
+        cfg.erase_count = stats.erase_count.saturating_add(1);
+        cfg.write_count = stats.write_count.saturating_add(1);
+        self.program_block(0, &cfg)
+    }
+
+    fn erase_flash(&mut self) -> Result<(), ConfigError> {
+        self.generation = self.generation.wrapping_add(1);
+        erase_sector(&mut self.flash, FLASH_SECTOR)
+    }
+
+    pub fn write_config(&mut self, cfg: &ConfigBlock) -> Result<(), ConfigError> {
+        self.write_config_with_secret(cfg, None)
+    }
+
+    pub fn write_config_with_secret(&mut self, cfg: &ConfigBlock, secret: Option<&[u8]>) -> Result<(), ConfigError> {
+        self.authorize(secret)?;
+        // the lock can only be changed with lock() and unlock()
+        let current = self.get();
+        self.store(cfg, current.locked, current.lock_secret)
+    }
+
+    fn store(&mut self, cfg: &ConfigBlock, locked: u8, lock_secret: [u8; LOCK_SECRET_LENGTH]) -> Result<(), ConfigError> {
+        // statistics are read before a possible erase, and always come from flash
+        let stats = self.stats();
+        let mut erase_count = stats.erase_count;
         let next = self.flash_config.get_next();
         let next_i: usize;
         match next {
@@ -144,17 +389,26 @@ impl ConfigArea {
                 next_i = i;
             },
             None => {
-                self.erase_flash();
+                self.erase_flash()?;
+                erase_count = erase_count.saturating_add(1);
                 next_i = 0;
             },
         }
-        let offset = next_i * size_of::<ConfigBlock>();
-        let mut unlocked_flash = self.flash.unlocked();
-        let buffer = unsafe { as_u8_slice(cfg) };
-        let base = FLASH_CONFIG_BASE - FLASH_BASE;
-        unlocked_flash.program(base + offset, buffer.iter()).unwrap();
 
-        Ok(())
+        let mut block = ConfigBlock::new();
+        unsafe { as_mut_u8_slice(&mut block) }.copy_from_slice(unsafe { as_u8_slice(cfg) });
+        block.erase_count = erase_count;
+        block.write_count = stats.write_count.saturating_add(1);
+        block.locked = locked;
+        block.lock_secret = lock_secret;
+        self.program_block(next_i, &block)
+    }
+
+    fn program_block(&mut self, index: usize, cfg: &ConfigBlock) -> Result<(), ConfigError> {
+        let offset = index * size_of::<ConfigBlock>();
+        let buffer = unsafe { as_u8_slice(cfg) };
+        self.generation = self.generation.wrapping_add(1);
+        program_flash(&mut self.flash, FLASH_CONFIG_BASE + offset, buffer)
     }
 }
 
@@ -165,7 +419,7 @@ impl ConfigAreaFlash {
     }
 
     fn get_next(&self) -> Option<usize> {
-        for i in 0..16 {
+        for i in 0..CONFIG_SLOTS {
             if !self.config[i].is_valid() {
                 return Some(i)
             }
@@ -175,7 +429,7 @@ impl ConfigAreaFlash {
 
     // detect if any of the config blocks have a format error (magic word is not 0x600dbeef of 0xffffffff)
     pub fn format_error(&self) -> bool {
-        for i in 0..16 {
+        for i in 0..CONFIG_SLOTS {
             if self.config[i].format_error() {
                 return true
             }
@@ -184,7 +438,7 @@ impl ConfigAreaFlash {
     }
 
     fn get_current(&self) -> Option<usize> {
-        for i in (0..16).rev() {
+        for i in (0..CONFIG_SLOTS).rev() {
             if self.config[i].is_valid() {
                 return Some(i)
             }
@@ -216,14 +470,39 @@ impl ConfigAreaFlash {
 }
 
 
-unsafe fn as_u8_slice<T: Sized>(p: &T) -> &[u8] {
+pub(crate) fn erase_sector(flash: &mut LockedFlash, sector: u8) -> Result<(), ConfigError> {
+    let mut unlocked_flash = flash.unlocked();
+    unlocked_flash.erase(sector).map_err(|_| ConfigError::Erase)
+}
+
+// program data at an absolute flash address and verify it
+pub(crate) fn program_flash(flash: &mut LockedFlash, address: usize, data: &[u8]) -> Result<(), ConfigError> {
+    {
+        let mut unlocked_flash = flash.unlocked();
+        unlocked_flash.program(address - FLASH_BASE, data.iter()).map_err(|_| ConfigError::Program)?;
+    }
+    verify_flash(address, data)
+}
+
+// read back programmed data, volatile reads so the compiler can't assume flash didn't change
+fn verify_flash(address: usize, data: &[u8]) -> Result<(), ConfigError> {
+    let flash = address as *const u8;
+    for (i, b) in data.iter().enumerate() {
+        if unsafe { flash.add(i).read_volatile() } != *b {
+            return Err(ConfigError::Verify);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) unsafe fn as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     ::core::slice::from_raw_parts(
         (p as *const T) as *mut u8,
         ::core::mem::size_of::<T>(),
     )
 }
 
-unsafe fn as_mut_u8_slice<T: Sized>(p: &T) -> &mut [u8] {
+pub(crate) unsafe fn as_mut_u8_slice<T: Sized>(p: &T) -> &mut [u8] {
     ::core::slice::from_raw_parts_mut(
         (p as *const T) as *mut u8,
         ::core::mem::size_of::<T>(),
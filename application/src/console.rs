@@ -0,0 +1,201 @@
+// Capture of everything received from the DUT UART, so the console can be read through the
+// control interface while the CDC serial port is in use by someone else.
+//
+// Bytes are addressed by a cursor counting every byte received since boot, the buffer keeps
+// the last CAPTURE_SIZE of them. Readers keep their own cursor, older bytes are lost when the
+// reader falls more than CAPTURE_SIZE bytes behind. Reads report how many were lost, and a
+// cursor ahead of the capture, i.e. kept from before a reboot, as UNKNOWN_GAP.
+//
+// The time the first byte of each line was received is kept for the last LINE_MARKS lines,
+// so the host can timestamp the console output, see clock.rs.
+
+use crate::clock::{self, Timestamp, TIMESTAMP_SIZE};
+
+pub const CAPTURE_SIZE: usize = 2048;
+pub const LINE_MARK_SIZE: usize = 4 + TIMESTAMP_SIZE;
+const LINE_MARKS: usize = 64;
+// bytes lost when the cursor of a read was ahead of the capture
+pub const UNKNOWN_GAP: u32 = u32::MAX;
+
+#[derive(Clone, Copy)]
+struct LineMark {
+    cursor: u32,          // first byte of the line
+    timestamp: Timestamp, // when it was received
+}
+
+pub struct ConsoleCapture {
+    buf: [u8; CAPTURE_SIZE],
+    end: u32,   // cursor of the next byte received, wraps around
+    full: bool, // the whole buffer holds captured bytes
+    lines: heapless::Deque<LineMark, LINE_MARKS>,
+    line_start: bool, // the next byte starts a line
+}
+
+impl ConsoleCapture {
+    pub fn new() -> Self {
+        ConsoleCapture {
+            buf: [0; CAPTURE_SIZE],
+            end: 0,
+            full: false,
+            lines: heapless::Deque::new(),
+            line_start: true,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.line_start {
+            if self.lines.is_full() {
+                self.lines.pop_front();
+            }
+            self.lines.push_back(LineMark { cursor: self.end, timestamp: clock::timestamp() }).ok();
+        }
+        self.line_start = byte == b'\n';
+        self.buf[self.end as usize % CAPTURE_SIZE] = byte;
+        self.end = self.end.wrapping_add(1);
+        self.full |= self.end as usize >= CAPTURE_SIZE;
+    }
+
+    // the control requests only carry the low 16 bits of the cursor, take the cursor
+    // with those bits closest to the end, CAPTURE_SIZE is well below 32k
+    pub fn expand_cursor(&self, low: u16) -> u32 {
+        let ahead = low.wrapping_sub(self.end as u16) as i16;
+        self.end.wrapping_add(ahead as i32 as u32)
+    }
+
+    // copy the bytes from cursor on into out, returns the cursor of the first byte copied
+    // and the number of bytes lost before it. A cursor ahead of the capture starts over
+    // from the oldest byte, reporting UNKNOWN_GAP
+    pub fn read<const N: usize>(&self, cursor: u32, out: &mut heapless::Vec<u8, N>) -> (u32, u32) {
+        let available = if self.full { CAPTURE_SIZE as u32 } else { self.end };
+        let oldest = self.end.wrapping_sub(available);
+        let behind = self.end.wrapping_sub(cursor);
+        let (start, lost) = if behind > u32::MAX / 2 {
+            (oldest, UNKNOWN_GAP)
+        } else if behind > available {
+            (oldest, behind - available)
+        } else {
+            (cursor, 0)
+        };
+
+        let mut c = start;
+        while c != self.end && out.push(self.buf[c as usize % CAPTURE_SIZE]).is_ok() {
+            c = c.wrapping_add(1);
+        }
+        (start, lost)
+    }
+
+    // append the marks of the lines starting at cursor or later to out, LINE_MARK_SIZE bytes
+    // each: cursor of the first byte of the line (u32 LE), Timestamp it was received at
+    pub fn line_marks<const N: usize>(&self, cursor: u32, out: &mut heapless::Vec<u8, N>) {
+        let behind = self.end.wrapping_sub(cursor);
+        for mark in self.lines.iter().filter(|m| self.end.wrapping_sub(m.cursor) <= behind) {
+            if out.capacity() - out.len() < LINE_MARK_SIZE {
+                break;
+            }
+            out.extend_from_slice(&mark.cursor.to_le_bytes()).ok();
+            out.extend_from_slice(&mark.timestamp.to_bytes()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(bytes: usize) -> ConsoleCapture {
+        let mut console = ConsoleCapture::new();
+        for i in 0..bytes {
+            console.push(i as u8);
+        }
+        console
+    }
+
+    fn read(console: &ConsoleCapture, cursor: u32) -> (u32, u32, heapless::Vec<u8, CAPTURE_SIZE>) {
+        let mut out = heapless::Vec::new();
+        let (start, lost) = console.read(cursor, &mut out);
+        (start, lost, out)
+    }
+
+    #[test]
+    fn read_from_cursor() {
+        let console = capture(10);
+        let (start, lost, out) = read(&console, 4);
+        assert_eq!((start, lost), (4, 0));
+        assert_eq!(&out[..], [4, 5, 6, 7, 8, 9]);
+        let (start, lost, out) = read(&console, 10);
+        assert_eq!((start, lost, out.len()), (10, 0, 0));
+    }
+
+    #[test]
+    fn read_is_limited_by_out() {
+        let console = capture(10);
+        let mut out = heapless::Vec::<u8, 4>::new();
+        assert_eq!(console.read(2, &mut out), (2, 0));
+        assert_eq!(&out[..], [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn wrap_around() {
+        let console = capture(CAPTURE_SIZE + 100);
+        let (start, lost, out) = read(&console, CAPTURE_SIZE as u32);
+        assert_eq!((start, lost), (CAPTURE_SIZE as u32, 0));
+        assert_eq!(out.len(), 100);
+        assert!(out.iter().enumerate().all(|(i, b)| *b == (CAPTURE_SIZE + i) as u8));
+    }
+
+    #[test]
+    fn cursor_overrun() {
+        let console = capture(CAPTURE_SIZE + 100);
+        let (start, lost, out) = read(&console, 40);
+        assert_eq!((start, lost), (100, 60));
+        assert_eq!(out.len(), CAPTURE_SIZE);
+        assert_eq!(out[0], 100);
+    }
+
+    #[test]
+    fn future_cursor() {
+        let console = capture(10);
+        let (start, lost, out) = read(&console, 11);
+        assert_eq!((start, lost), (0, UNKNOWN_GAP));
+        assert_eq!(out.len(), 10);
+
+        let console = capture(CAPTURE_SIZE + 100);
+        let (start, lost, _) = read(&console, 5000);
+        assert_eq!((start, lost), (100, UNKNOWN_GAP));
+    }
+
+    #[test]
+    fn cursor_wraps_at_32_bits() {
+        let mut console = capture(0);
+        console.end = u32::MAX - 1;
+        console.full = true;
+        for b in 0..4 {
+            console.push(b);
+        }
+        let (start, lost, out) = read(&console, u32::MAX - 1);
+        assert_eq!((start, lost), (u32::MAX - 1, 0));
+        assert_eq!(&out[..], [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn expand_cursor() {
+        let console = capture(70_000);
+        assert_eq!(console.expand_cursor(70_000u32 as u16), 70_000);
+        assert_eq!(console.expand_cursor(69_000u32 as u16), 69_000);
+        // a cursor ahead of the capture stays ahead, to be reported by read
+        assert_eq!(console.expand_cursor(70_010u32 as u16), 70_010);
+        assert_eq!(capture(10).expand_cursor(0xfff0), 0u32.wrapping_sub(16));
+    }
+
+    #[test]
+    fn line_marks() {
+        let mut console = ConsoleCapture::new();
+        for b in b"one\ntwo\nthree" {
+            console.push(*b);
+        }
+        let mut out = heapless::Vec::<u8, { LINE_MARK_SIZE * 4 }>::new();
+        console.line_marks(4, &mut out);
+        let cursors: Vec<u32> = out.chunks(LINE_MARK_SIZE).map(|m| u32::from_le_bytes([m[0], m[1], m[2], m[3]])).collect();
+        assert_eq!(cursors, [4, 8]);
+    }
+}
\ No newline at end of file
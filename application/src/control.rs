@@ -6,16 +6,37 @@ use usb_device::class_prelude::*;
 use usb_device::control::{Recipient, Request, RequestType};
 use usb_device::Result;
 
-use crate::config::{ConfigArea, ConfigBlock};
+use crate::clock;
+use crate::console::{ConsoleCapture, CAPTURE_SIZE, LINE_MARK_SIZE};
+use crate::config::{value_bytes, ConfigArea, ConfigBlock, ConfigError, ConfigStats, FACTORY_RESET_TOKEN, LOCK_SECRET_LENGTH};
 use crate::ctlpins::{CTLPinsTrait, PinState};
-use crate::powermeter::PowerMeter;
-use crate::storage::StorageSwitchTrait;
+use crate::events::{EventKind, EventQueue};
+use crate::lease::{Lease, LeaseError, LEASE_TOKEN_LENGTH, MAX_LEASE_S};
+use crate::msos;
+use crate::powermeter::PowerReadings;
+use crate::profiles::MAX_PROFILES;
+use crate::status::{DeviceStatus, FAULT_CONFIG_WRITE};
+use crate::storage::{StorageState, StorageSwitchTrait};
+use crate::sequence::{self, SequenceState, Sequencer, MAX_SEQUENCE_LENGTH};
+use crate::stream::{SampleStream, StreamFormat, DEFAULT_RATE_HZ, MAX_RATE_HZ, PACKET_SIZE};
 
 const USB_CLASS_VENDOR_SPECIFIC: u8 = 0xff;
 const USB_SUBCLASS_JUMPSTARTER: u8 = 0x01;
 const USB_PROTOCOL_JUMPSTARTER: u8 = 0x01;
+// a string descriptor holds up to 126 UTF-16 characters, at most as many as the bytes in UTF-8
+const MAX_STRING_LENGTH: usize = 126;
 const MAX_CONFIG_LENGTH: usize = 256;
+// largest config value, the json blob, only reachable with chunked transfers
+const MAX_VALUE_LENGTH: usize = 512;
 const MAX_READ_LENGTH: usize = 128;
+// version of the vendor interface protocol, bumped on incompatible changes
+const PROTOCOL_VERSION: u8 = 1;
+const QUEUE_LENGTH: usize = 8;    // operations waiting for post_poll
+const HISTORY_LENGTH: usize = 16; // operations whose state can be queried
+// error code of a sequence aborted, or replaced by another one, before reaching its end
+const CODE_ABORTED: u8 = 0x80;
+// the secret of an Authorize is dropped when the next operation doesn't come within this time
+const AUTHORIZE_TIMEOUT_MS: u64 = 1000;
 
 #[repr(u8)]
 #[derive(TryFromPrimitive)]
@@ -26,6 +47,25 @@ pub enum ControlRequest {
     Config,
     Read,
     Set,
+    FactoryReset,
+    ConfigStatus,
+    ConfigStats,
+    Lock,
+    Profile,
+    Capabilities,
+    Status,
+    Stream,
+    ConfigRead,
+    ConfigWrite,
+    ConfigCommit,
+    Sequence,
+    ConsoleRead,
+    ConsoleWrite,
+    Operation,
+    Lease,
+    Reboot,
+    Time,
+    ConsoleTimes,
 }
 
 #[repr(u16)]
@@ -48,6 +88,45 @@ pub enum StorageAction {
 
 #[repr(u16)]
 #[derive(TryFromPrimitive)]
+pub enum TimeAction {
+    Set,   // step to the epoch time in the data stage
+    Sync,  // same as Set, also correcting the drift with the error since the previous sync
+    Drift, // the data stage carries the drift correction in ppm
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum RebootAction {
+    Reboot,     // restart the application
+    Bootloader, // stay in the DFU bootloader
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum LeaseAction {
+    Acquire, // the data stage carries the duration in seconds (u16 LE) followed by the token
+    Renew,   // same as Acquire, only for the holder
+    Release, // the data stage carries the token
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum LockAction {
+    Unlock,    // remove the lock, the data stage carries the secret
+    Lock,      // lock the config, the data stage carries the new secret
+    Authorize, // the data stage carries the secret for the next request, if a Config, FactoryReset or Profile one
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum StreamAction {
+    Stop,
+    StartRaw,       // the optional data stage carries the sampling rate in Hz (u16 LE)
+    StartConverted, // same as StartRaw, with samples converted to mV and mA
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive, Clone, Copy, PartialEq)]
 pub enum ConfigKey {
     Name,
     Tags,
@@ -55,6 +134,7 @@ pub enum ConfigKey {
     PowerOn,
     PowerOff,
     PowerRescue,
+    Json,
 }
 
 #[repr(u16)]
@@ -84,141 +164,538 @@ pub enum SetPinState {
     Floating,
 }
 
-pub struct ControlClass {
+// Actions requested by the host. They are queued by control_out, which runs inside poll and
+// can't reach the other resources, and executed in order by post_poll.
+enum Action {
+    Config(ConfigKey, heapless::Vec<u8, MAX_VALUE_LENGTH>),
+    FactoryReset,
+    Lock(LockAction, heapless::Vec<u8, LOCK_SECRET_LENGTH>),
+    Profile(u8),
+    Power(PowerAction),
+    Storage(StorageAction),
+    Pin(SetPin, SetPinState),
+    Stream(StreamAction, u16),
+    Sequence(heapless::Vec<u8, MAX_SEQUENCE_LENGTH>), // empty to abort
+    Reboot(RebootAction),
+}
+
+struct Operation {
+    id: u16,
+    action: Action,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+enum OperationState {
+    Unknown = 0, // never accepted, or too old to be remembered
+    Queued = 1,
+    Running = 2, // only sequences and reboots keep running after post_poll
+    Done = 3,
+    Failed = 4,  // the error code tells why
+}
+
+#[derive(Clone, Copy)]
+struct OperationResult {
+    id: u16,
+    state: OperationState,
+    code: u8, // ConfigError::code or CODE_ABORTED when failed
+}
+
+pub struct ControlClass<'a, B: UsbBus> {
     iface: InterfaceNumber,
-    config: Option<(ConfigKey, heapless::Vec<u8, MAX_CONFIG_LENGTH>)>,
-    power: Option<PowerAction>,
-    storage: Option<StorageAction>,
-    pin: Option<(SetPin, SetPinState)>,
-    refresh: Option<()>,
+    // The STM32F411 has only 4 IN endpoints, and EP0 and the CDC serial port take 3 of them,
+    // so power samples and events share the last one. An interrupt endpoint polled every
+    // 1ms gives the events a bounded latency and still has room for the samples.
+    in_ep: EndpointIn<'a, B>, // events and power samples, see events.rs and stream.rs
+    tags_string: StringIndex, // interface string with the DUT tags, for udev rules
+    watched: Option<Watched>, // state last reported through events
+    queue: heapless::Deque<Operation, QUEUE_LENGTH>,
+    results: heapless::Deque<OperationResult, HISTORY_LENGTH>,
+    next_id: u16,                   // ids start at 1, 0 refers to the last operation of the lease holder
+    submitter: Option<heapless::Vec<u8, LEASE_TOKEN_LENGTH>>, // lease token of the request being handled
+    holder_last_id: Option<(heapless::Vec<u8, LEASE_TOKEN_LENGTH>, u16)>, // last operation accepted with a lease token
+    running_sequence: Option<u16>,  // operation id of the sequence in the Sequencer
+    staged: Option<heapless::Vec<u8, MAX_VALUE_LENGTH>>, // chunked config write in progress
+    stop_stream: bool,              // set on bus reset, the stream is stopped by post_poll
+    secret: Option<(heapless::Vec<u8, LOCK_SECRET_LENGTH>, u64)>, // unlock secret for the next operation, with its expiry uptime
+    lease: Lease,
+    console: ConsoleCapture, // bytes received from the DUT
+    console_tx: heapless::Deque<u8, MAX_CONFIG_LENGTH>, // bytes waiting to be sent to the DUT
+    config_status: u8, // result of the last config write, see ConfigError::code
     data: Data,
 }
 
+// device state changes are turned into events by comparing it with the last one seen
+#[derive(PartialEq)]
+struct Watched {
+    power: bool,
+    storage: StorageState,
+    config_generation: u32,
+    sequences: u32,
+}
+
+// copy of the device state served to IN requests, see ControlClass::update
 pub struct Data {
-    power: f32,
-    voltage: f32,
-    current: f32,
+    readings: PowerReadings,
     config: ConfigBlock,
+    config_generation: Option<u32>, // ConfigArea::generation of the cached config
+    stats: ConfigStats,
+    status: DeviceStatus,
+    stream_status: heapless::Vec<u8, 9>,
+    sequence_status: heapless::Vec<u8, 5>,
 }
 
-impl ControlClass {
-    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> Self {
+impl<'a, B: UsbBus> ControlClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
         Self {
             iface: alloc.interface(),
-            power: None,
-            storage: None,
-            pin: None,
-            config: None,
-            refresh: None,
+            in_ep: alloc.interrupt(PACKET_SIZE as u16, 1),
+            tags_string: alloc.string(),
+            watched: None,
+            queue: heapless::Deque::new(),
+            results: heapless::Deque::new(),
+            next_id: 1,
+            submitter: None,
+            holder_last_id: None,
+            running_sequence: None,
+            staged: None,
+            stop_stream: false,
+            secret: None,
+            lease: Lease::new(),
+            console: ConsoleCapture::new(),
+            console_tx: heapless::Deque::new(),
+            config_status: 0,
             data: Data {
-                power: 0.0,
-                voltage: 0.0,
-                current: 0.0,
+                readings: PowerReadings::new(),
                 config: ConfigBlock::new(),
+                config_generation: None,
+                stats: ConfigStats::new(),
+                status: DeviceStatus::new(),
+                stream_status: heapless::Vec::new(),
+                sequence_status: heapless::Vec::new(),
             },
         }
     }
+    // Refresh the data served to IN requests, must be called before polling the device.
+    // IN requests are answered from within poll, where the other resources can't be reached,
+    // so the readings published by the ADC task and the rest of the state are copied here.
+    // The config is only copied again when it has changed.
+    pub fn update<C: CTLPinsTrait, S: StorageSwitchTrait>(
+        &mut self,
+        config: &ConfigArea,
+        ctlpins: &C,
+        storage: &S,
+        readings: &PowerReadings,
+        stream: &SampleStream,
+        sequencer: &Sequencer,
+    ) {
+        if self.data.config_generation != Some(config.generation()) {
+            self.data.config = config.get();
+            self.data.stats = config.stats();
+            self.data.config_generation = Some(config.generation());
+        }
+        self.data.readings = *readings;
+        self.data.status = DeviceStatus::capture(ctlpins, storage, readings, &self.data.stats);
+        if self.config_status != 0 {
+            self.data.status.set_fault(FAULT_CONFIG_WRITE);
+        }
+        self.data.stream_status = stream.status();
+        self.data.sequence_status = sequencer.status();
+
+        if let Some(id) = self.running_sequence {
+            match sequencer.state() {
+                SequenceState::Running => {}
+                SequenceState::Aborted => {
+                    self.set_result(id, OperationState::Failed, CODE_ABORTED);
+                    self.running_sequence = None;
+                }
+                _ => {
+                    self.set_result(id, OperationState::Done, 0);
+                    self.running_sequence = None;
+                }
+            }
+        }
+    }
+
+    // queue an action, returns false when the queue is full
+    fn enqueue(&mut self, action: Action) -> bool {
+        if self.queue.is_full() {
+            return false;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.queue.push_back(Operation { id, action }).ok();
+        if self.results.is_full() {
+            self.results.pop_front();
+        }
+        self.results.push_back(OperationResult { id, state: OperationState::Queued, code: 0 }).ok();
+        if let Some(token) = self.submitter.take() {
+            self.holder_last_id = Some((token, id));
+        }
+        true
+    }
+
+    fn accept_action(&mut self, xfer: ControlOut<B>, action: Action) {
+        if self.enqueue(action) {
+            xfer.accept().unwrap();
+        } else {
+            xfer.reject().unwrap();
+        }
+    }
+
+    fn set_result(&mut self, id: u16, state: OperationState, code: u8) {
+        if let Some(r) = self.results.iter_mut().find(|r| r.id == id) {
+            r.state = state;
+            r.code = code;
+        }
+    }
+
+    // result of a config operation done without this interface, i.e. the button factory reset
+    pub fn report_config_result(&mut self, result: core::result::Result<(), ConfigError>) {
+        self.config_status = status_code(result);
+    }
+
+    // the result of config operations is also reported by ConfigStatus
+    fn config_result(&mut self, result: core::result::Result<(), ConfigError>) -> (OperationState, u8) {
+        self.config_status = status_code(result);
+        match result {
+            Ok(()) => (OperationState::Done, 0),
+            Err(e) => (OperationState::Failed, e.code()),
+        }
+    }
+
     pub fn post_poll<C: CTLPinsTrait, S: StorageSwitchTrait>(
         &mut self,
         config: &mut ConfigArea,
         ctlpins: &mut C,
         storage: &mut S,
-        power_meter: &mut dyn PowerMeter,
+        stream: &mut SampleStream,
+        sequencer: &mut Sequencer,
     ) {
-        if let Some((key, value)) = self.config.take() {
-            match key {
-                ConfigKey::Name => {
-                    let cfg = config.get().set_name(&value);
-                    config.write_config(&cfg).ok();
-                }
-                ConfigKey::Tags => {
-                    let cfg = config.get().set_tags(&value);
-                    config.write_config(&cfg).ok();
-                }
-                ConfigKey::UsbConsole => {
-                    let cfg = config.get().set_usb_console(&value);
-                    config.write_config(&cfg).ok();
-                }
-                ConfigKey::PowerOn => {
-                    let cfg = config.get().set_power_on(&value);
-                    config.write_config(&cfg).ok();
-                }
-                ConfigKey::PowerOff => {
-                    let cfg = config.get().set_power_off(&value);
-                    config.write_config(&cfg).ok();
-                }
-                ConfigKey::PowerRescue => {
-                    let cfg = config.get().set_power_rescue(&value);
-                    config.write_config(&cfg).ok();
+        if self.stop_stream {
+            stream.stop();
+            self.stop_stream = false;
+        }
+        while let Some(op) = self.queue.pop_front() {
+            let id = op.id;
+            let (state, code) = self.execute(op, config, ctlpins, storage, stream, sequencer);
+            self.set_result(id, state, code);
+        }
+    }
+
+    fn execute<C: CTLPinsTrait, S: StorageSwitchTrait>(
+        &mut self,
+        op: Operation,
+        config: &mut ConfigArea,
+        ctlpins: &mut C,
+        storage: &mut S,
+        stream: &mut SampleStream,
+        sequencer: &mut Sequencer,
+    ) -> (OperationState, u8) {
+        // the secret of an Authorize only applies to the operation right after it
+        let now = crate::uptime_ms();
+        let secret = self.secret.take().filter(|(_, expires_ms)| now < *expires_ms).map(|(secret, _)| secret);
+        match op.action {
+            Action::Config(key, value) => {
+                let cfg = config.get();
+                let cfg = match key {
+                    ConfigKey::Name => cfg.set_name(&value),
+                    ConfigKey::Tags => cfg.set_tags(&value),
+                    ConfigKey::UsbConsole => cfg.set_usb_console(&value),
+                    ConfigKey::PowerOn => cfg.set_power_on(&value),
+                    ConfigKey::PowerOff => cfg.set_power_off(&value),
+                    ConfigKey::PowerRescue => cfg.set_power_rescue(&value),
+                    ConfigKey::Json => cfg.set_json(&value),
+                };
+                let result = cfg.and_then(|cfg| config.write_config_with_secret(&cfg, secret.as_deref()));
+                self.config_result(result)
+            }
+            Action::FactoryReset => {
+                let result = config.factory_reset(secret.as_deref());
+                self.config_result(result)
+            }
+            Action::Lock(action, lock_secret) => {
+                let result = match action {
+                    LockAction::Unlock => config.unlock(&lock_secret),
+                    LockAction::Lock => config.lock(&lock_secret),
+                    LockAction::Authorize => {
+                        self.secret = Some((lock_secret, now + AUTHORIZE_TIMEOUT_MS));
+                        Ok(())
+                    }
+                };
+                self.config_result(result)
+            }
+            Action::Profile(id) => {
+                let result = config.load_profile(id, secret.as_deref()).map(|pins| ctlpins.set_pins(&pins));
+                self.config_result(result)
+            }
+            Action::Power(action) => {
+                // a config operation queued before this one may have changed the sequences
+                let cfg = config.get();
+                match action {
+                    PowerAction::Off => {
+                        ctlpins.power_off(&cfg.power_off);
+                    }
+                    PowerAction::On => {
+                        ctlpins.power_on(&cfg.power_on);
+                    }
+                    PowerAction::ForceOff => {
+                        ctlpins.power_off(&[]);
+                    }
+                    PowerAction::ForceOn => {
+                        ctlpins.power_on(&[]);
+                    }
+                    PowerAction::Rescue => {
+                        ctlpins.power_on(&cfg.power_rescue);
+                    }
                 }
+                (OperationState::Done, 0)
             }
-        }
-        if let Some(action) = self.power.take() {
-            match action {
-                PowerAction::Off => {
-                    ctlpins.power_off(&self.data.config.power_off);
+            Action::Storage(action) => {
+                match action {
+                    StorageAction::Off => {
+                        storage.power_off();
+                    }
+                    StorageAction::Host => {
+                        storage.connect_to_host();
+                    }
+                    StorageAction::DUT => {
+                        storage.connect_to_dut();
+                    }
                 }
-                PowerAction::On => {
-                    ctlpins.power_on(&self.data.config.power_on);
+                (OperationState::Done, 0)
+            }
+            Action::Pin(pin, state) => {
+                let state = match state {
+                    SetPinState::Low => PinState::Low,
+                    SetPinState::High => PinState::High,
+                    SetPinState::Floating => PinState::Floating,
+                };
+                match pin {
+                    SetPin::Reset => {
+                        ctlpins.set_reset(state);
+                    }
+                    SetPin::A => {
+                        ctlpins.set_ctl_a(state);
+                    }
+                    SetPin::B => {
+                        ctlpins.set_ctl_b(state);
+                    }
+                    SetPin::C => {
+                        ctlpins.set_ctl_c(state);
+                    }
+                    SetPin::D => {
+                        ctlpins.set_ctl_d(state);
+                    }
                 }
-                PowerAction::ForceOff => {
-                    ctlpins.power_off(&[]);
+                (OperationState::Done, 0)
+            }
+            Action::Stream(action, rate) => {
+                match action {
+                    StreamAction::Stop => stream.stop(),
+                    StreamAction::StartRaw => stream.start(StreamFormat::Raw, rate),
+                    StreamAction::StartConverted => stream.start(StreamFormat::Converted, rate),
                 }
-                PowerAction::ForceOn => {
-                    ctlpins.power_on(&[]);
+                (OperationState::Done, 0)
+            }
+            Action::Sequence(sequence) => {
+                if let Some(previous) = self.running_sequence.take() {
+                    self.set_result(previous, OperationState::Failed, CODE_ABORTED);
                 }
-                PowerAction::Rescue => {
-                    ctlpins.power_on(&self.data.config.power_rescue);
+                if sequence.is_empty() {
+                    sequencer.abort();
+                    return (OperationState::Done, 0);
                 }
+                // validated when the request was accepted
+                sequencer.start(&sequence).ok();
+                self.running_sequence = Some(op.id);
+                (OperationState::Running, 0)
+            }
+            Action::Reboot(action) => {
+                crate::dfu::request_reboot(matches!(action, RebootAction::Bootloader));
+                (OperationState::Running, 0)
             }
         }
-        if let Some(action) = self.storage.take() {
-            match action {
-                StorageAction::Off => {
-                    storage.power_off();
-                }
-                StorageAction::Host => {
-                    storage.connect_to_host();
-                }
-                StorageAction::DUT => {
-                    storage.connect_to_dut();
-                }
+    }
+
+    // queue events for the changes made since the last call, by the shell, the control
+    // interface or other tasks
+    pub fn watch<C: CTLPinsTrait, S: StorageSwitchTrait>(
+        &mut self,
+        config: &ConfigArea,
+        ctlpins: &C,
+        storage: &S,
+        events: &mut EventQueue,
+    ) {
+        let now = Watched {
+            power: ctlpins.is_on(),
+            storage: storage.get_state(),
+            config_generation: config.generation(),
+            sequences: ctlpins.sequence_count(),
+        };
+        if let Some(last) = &self.watched {
+            if now.sequences != last.sequences {
+                events.push(EventKind::SequenceComplete, now.power as u8);
+            }
+            if now.power != last.power {
+                events.push(EventKind::PowerChanged, now.power as u8);
+            }
+            if now.storage != last.storage {
+                let arg = match now.storage {
+                    StorageState::Off => 0,
+                    StorageState::Host => 1,
+                    StorageState::DUT => 2,
+                };
+                events.push(EventKind::StorageChanged, arg);
+            }
+            if now.config_generation != last.config_generation {
+                events.push(EventKind::ConfigChanged, 0);
             }
         }
-        if let Some((pin, state)) = self.pin.take() {
-            let state = match state {
-                SetPinState::Low => PinState::Low,
-                SetPinState::High => PinState::High,
-                SetPinState::Floating => PinState::Floating,
-            };
-            match pin {
-                SetPin::Reset => {
-                    ctlpins.set_reset(state);
-                }
-                SetPin::A => {
-                    ctlpins.set_ctl_a(state);
-                }
-                SetPin::B => {
-                    ctlpins.set_ctl_b(state);
-                }
-                SetPin::C => {
-                    ctlpins.set_ctl_c(state);
-                }
-                SetPin::D => {
-                    ctlpins.set_ctl_d(state);
-                }
+        self.watched = Some(now);
+    }
+
+    // lease of the control interface, reported by the shell
+    pub fn lease(&self) -> &Lease {
+        &self.lease
+    }
+
+    // device status as of the last update, with the faults latched by this interface
+    pub fn status(&self) -> &DeviceStatus {
+        &self.data.status
+    }
+
+    // called by the UART task for every byte received from the DUT
+    pub fn capture_console(&mut self, byte: u8) {
+        self.console.push(byte);
+    }
+
+    // hand the bytes written with ConsoleWrite to send, one by one until it returns false
+    pub fn write_console<F: FnMut(u8) -> bool>(&mut self, mut send: F) {
+        while let Some(b) = self.console_tx.front() {
+            if !send(*b) {
+                break;
             }
+            self.console_tx.pop_front();
         }
-        if let Some(()) = self.refresh.take() {
-            self.data.power = power_meter.get_power();
-            self.data.voltage = power_meter.get_voltage();
-            self.data.current = power_meter.get_current();
-            self.data.config = config.get();
+    }
+
+    // send the next packet if the endpoint is free, events go before samples. Both
+    // stay queued until the endpoint accepts them
+    pub fn write_in(&mut self, stream: &mut SampleStream, events: &mut EventQueue) {
+        if let Some(packet) = events.next_packet() {
+            if self.in_ep.write(&packet).is_ok() {
+                events.consume(packet[1] as usize);
+            }
+        } else if let Some(packet) = stream.next_packet() {
+            if self.in_ep.write(&packet).is_ok() {
+                stream.consume(packet[1] as usize);
+            }
         }
     }
 }
 
-impl<B: UsbBus> UsbClass<B> for ControlClass {
+// bitmap of the values accepted by an enum, bit n is set when n is a valid value
+fn supported<T>(count: u8) -> u32
+where
+    T: TryFromPrimitive,
+    T::Primitive: From<u8>,
+{
+    (0..count)
+        .filter(|n| T::try_from_primitive(T::Primitive::from(*n)).is_ok())
+        .fold(0, |bits, n| bits | 1 << n)
+}
+
+// Capabilities descriptor, all values little endian:
+//   0  u8  length of the descriptor
+//   1  u8  protocol version
+//   2  u16 firmware version, BCD as in bcdDevice
+//   4  u8  board hardware revision
+//   5  u32 supported ControlRequest values, bit n for request n
+//   9  u8  supported PowerAction values
+//   10 u8  supported StorageAction values
+//   11 u8  supported SetPin values
+//   12 u8  supported SetPinState values
+//   13 u8  supported LockAction values
+//   14 u16 supported ConfigKey values
+//   16 u16 supported ReadKey values
+//   18 u16 max config value length
+//   20 u16 max read length
+//   22 u8  max unlock secret length
+//   23 u8  number of DUT profiles
+//   24 u8  supported StreamAction values
+//   25 u16 max sample stream rate in Hz
+//   27 u16 max config value length with chunked transfers
+//   29 u16 max pin sequence length
+//   31 u16 size of the DUT console capture buffer
+//   33 u8  number of operations that can be queued
+//   34 u8  supported LeaseAction values
+//   35 u8  max lease token length
+//   36 u16 max lease duration in seconds
+fn capabilities() -> heapless::Vec<u8, 64> {
+    let mut buf = heapless::Vec::<u8, 64>::new();
+    buf.push(0).ok();
+    buf.push(PROTOCOL_VERSION).ok();
+    buf.extend_from_slice(&crate::version::usb_version_bcd_device().to_le_bytes()).ok();
+    buf.push(crate::version::hardware_revision()).ok();
+    buf.extend_from_slice(&supported::<ControlRequest>(32).to_le_bytes()).ok();
+    buf.push(supported::<PowerAction>(8) as u8).ok();
+    buf.push(supported::<StorageAction>(8) as u8).ok();
+    buf.push(supported::<SetPin>(8) as u8).ok();
+    buf.push(supported::<SetPinState>(8) as u8).ok();
+    buf.push(supported::<LockAction>(8) as u8).ok();
+    buf.extend_from_slice(&(supported::<ConfigKey>(16) as u16).to_le_bytes()).ok();
+    buf.extend_from_slice(&(supported::<ReadKey>(16) as u16).to_le_bytes()).ok();
+    buf.extend_from_slice(&(MAX_CONFIG_LENGTH as u16).to_le_bytes()).ok();
+    buf.extend_from_slice(&(MAX_READ_LENGTH as u16).to_le_bytes()).ok();
+    buf.push(LOCK_SECRET_LENGTH as u8).ok();
+    buf.push(MAX_PROFILES as u8).ok();
+    buf.push(supported::<StreamAction>(8) as u8).ok();
+    buf.extend_from_slice(&MAX_RATE_HZ.to_le_bytes()).ok();
+    buf.extend_from_slice(&(MAX_VALUE_LENGTH as u16).to_le_bytes()).ok();
+    buf.extend_from_slice(&(MAX_SEQUENCE_LENGTH as u16).to_le_bytes()).ok();
+    buf.extend_from_slice(&(CAPTURE_SIZE as u16).to_le_bytes()).ok();
+    buf.push(QUEUE_LENGTH as u8).ok();
+    buf.push(supported::<LeaseAction>(8) as u8).ok();
+    buf.push(LEASE_TOKEN_LENGTH as u8).ok();
+    buf.extend_from_slice(&MAX_LEASE_S.to_le_bytes()).ok();
+    buf[0] = buf.len() as u8;
+    buf
+}
+
+fn config_field(cfg: &ConfigBlock, key: ConfigKey) -> &[u8] {
+    match key {
+        ConfigKey::Name => &cfg.name,
+        ConfigKey::Tags => &cfg.tags,
+        ConfigKey::UsbConsole => &cfg.usb_console,
+        ConfigKey::PowerOn => &cfg.power_on,
+        ConfigKey::PowerOff => &cfg.power_off,
+        ConfigKey::PowerRescue => &cfg.power_rescue,
+        ConfigKey::Json => &cfg.json,
+    }
+}
+
+// ConfigRead and ConfigCommit carry the ConfigKey in the high byte of wIndex,
+// the low byte is the interface number
+fn chunk_key(req: &Request) -> Option<ConfigKey> {
+    (req.index >> 8).try_into().ok()
+}
+
+// the lease token at the end of the data stage, its length is in the high byte of wIndex.
+// None when the data stage is shorter than the token
+fn split_token<'d>(req: &Request, data: &'d [u8]) -> Option<(&'d [u8], &'d [u8])> {
+    let length = (req.index >> 8) as usize;
+    data.len().checked_sub(length).map(|n| data.split_at(n))
+}
+
+fn status_code(result: core::result::Result<(), ConfigError>) -> u8 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.code(),
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for ControlClass<'_, B> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
         writer.iad(
             self.iface,
@@ -229,19 +706,58 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
             None,
         )?;
 
-        writer.interface(
+        writer.interface_alt(
             self.iface,
+            0,
             USB_CLASS_VENDOR_SPECIFIC,
             USB_SUBCLASS_JUMPSTARTER,
             USB_PROTOCOL_JUMPSTARTER,
+            Some(self.tags_string),
         )?;
 
+        writer.endpoint(&self.in_ep)?;
+
         Ok(())
     }
 
+    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
+        if index != self.tags_string {
+            return None;
+        }
+        // read by the host on enumeration, changes to the tags show up on the next one
+        let tags = value_bytes(&self.data.config.tags);
+        let tags = &tags[..tags.len().min(MAX_STRING_LENGTH)];
+        let tags = match core::str::from_utf8(tags) {
+            Ok(t) => t,
+            Err(e) => core::str::from_utf8(&tags[..e.valid_up_to()]).unwrap_or(""),
+        };
+        Some(if tags.is_empty() { "Dutlink control" } else { tags })
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        writer.capability(msos::PLATFORM_CAPABILITY, &msos::platform_capability())
+    }
+
+    fn reset(&mut self) {
+        // nobody is reading the samples anymore, this is not an operation of the host
+        self.stop_stream = true;
+        // nor is the host that sent the secret around anymore
+        self.secret = None;
+    }
+
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
 
+        // Windows asks for the MS OS 2.0 descriptor set with a vendor request to the device
+        if req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Device
+            && req.request == msos::VENDOR_CODE
+            && req.index == msos::DESCRIPTOR_INDEX
+        {
+            xfer.accept_with(&msos::descriptor_set(self.iface.into())).ok();
+            return;
+        }
+
         match req {
             &Request {
                 request_type: RequestType::Vendor,
@@ -275,11 +791,109 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
                         ConfigKey::PowerRescue => {
                             xfer.accept_with(&cfg.power_rescue).ok();
                         }
+                        ConfigKey::Json => {
+                            // larger than the control buffer, see ConfigRead
+                            xfer.reject().unwrap();
+                        }
                     }
                 } else {
                     xfer.reject().unwrap();
                 }
             }
+            Ok(ControlRequest::ConfigStatus) => {
+                xfer.accept_with(&[self.config_status]).ok();
+            }
+            Ok(ControlRequest::Capabilities) => {
+                xfer.accept_with(&capabilities()).ok();
+            }
+            Ok(ControlRequest::Status) => {
+                xfer.accept_with(self.data.status.as_bytes()).ok();
+            }
+            Ok(ControlRequest::Stream) => {
+                xfer.accept_with(&self.data.stream_status).ok();
+            }
+            Ok(ControlRequest::Sequence) => {
+                xfer.accept_with(&self.data.sequence_status).ok();
+            }
+            Ok(ControlRequest::Lease) => {
+                // remaining time in ms (u32 LE, 0 while free) followed by the token of the holder
+                let now = crate::uptime_ms();
+                let mut buf = heapless::Vec::<u8, { 4 + LEASE_TOKEN_LENGTH }>::new();
+                let remaining = self.lease.remaining_ms(now).min(u32::MAX as u64) as u32;
+                buf.extend_from_slice(&remaining.to_le_bytes()).ok();
+                buf.extend_from_slice(self.lease.holder(now).unwrap_or(&[])).ok();
+                xfer.accept_with(&buf).ok();
+            }
+            Ok(ControlRequest::Operation) => {
+                // wValue is the operation id, or 0 for the last one accepted from the current
+                // lease holder, so each host finds its own without racing the others. The reply
+                // is the id (u16 LE, 0 when there is none), the OperationState and the error code
+                let holder = self.lease.holder(crate::uptime_ms());
+                let id = match (req.value, &self.holder_last_id) {
+                    (0, Some((token, id))) if holder == Some(&token[..]) => *id,
+                    _ => req.value,
+                };
+                let result = self.results.iter().find(|r| r.id == id).copied()
+                    .unwrap_or(OperationResult { id, state: OperationState::Unknown, code: 0 });
+                let mut buf = heapless::Vec::<u8, 4>::new();
+                buf.extend_from_slice(&result.id.to_le_bytes()).ok();
+                buf.push(result.state as u8).ok();
+                buf.push(result.code).ok();
+                xfer.accept_with(&buf).ok();
+            }
+            Ok(ControlRequest::ConsoleRead) => {
+                // wValue holds the low 16 bits of the reader cursor, the reply starts with
+                // the cursor of the first byte returned (u32 LE) and the bytes lost before it
+                // (u32 LE, UNKNOWN_GAP for a cursor ahead of the capture), followed by the bytes
+                let mut bytes = heapless::Vec::<u8, { MAX_CONFIG_LENGTH - 8 }>::new();
+                let (cursor, lost) = self.console.read(self.console.expand_cursor(req.value), &mut bytes);
+                bytes.truncate((req.length as usize).saturating_sub(8));
+                let mut buf = heapless::Vec::<u8, MAX_CONFIG_LENGTH>::new();
+                buf.extend_from_slice(&cursor.to_le_bytes()).ok();
+                buf.extend_from_slice(&lost.to_le_bytes()).ok();
+                buf.extend_from_slice(&bytes).ok();
+                xfer.accept_with(&buf).ok();
+            }
+            Ok(ControlRequest::ConsoleTimes) => {
+                // wValue holds the low 16 bits of the reader cursor, the reply is the list of
+                // the lines starting there or later, see ConsoleCapture::line_marks
+                let mut buf = heapless::Vec::<u8, { LINE_MARK_SIZE * 10 }>::new();
+                self.console.line_marks(self.console.expand_cursor(req.value), &mut buf);
+                buf.truncate(req.length as usize / LINE_MARK_SIZE * LINE_MARK_SIZE);
+                xfer.accept_with(&buf).ok();
+            }
+            Ok(ControlRequest::Time) => {
+                xfer.accept_with(&clock::status()).ok();
+            }
+            Ok(ControlRequest::ConfigRead) => {
+                // wValue is the offset, wLength the size of the chunk, a short read marks the end
+                if let Some(key) = chunk_key(req) {
+                    let value = value_bytes(config_field(&self.data.config, key));
+                    let start = (req.value as usize).min(value.len());
+                    let end = (start + req.length as usize).min(value.len());
+                    xfer.accept_with(&value[start..end]).ok();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Profile) => {
+                // id of the active profile, 0xff if none
+                let active = self.data.config.active_profile().unwrap_or(0xff);
+                xfer.accept_with(&[active]).ok();
+            }
+            Ok(ControlRequest::ConfigStats) => {
+                // erase count (u32 LE), write count (u32 LE), active slot (0xff if none),
+                // number of slots, wear warning flag, profile sector erase count (u32 LE)
+                let stats = &self.data.stats;
+                let mut buf = heapless::Vec::<u8, 15>::new();
+                buf.extend_from_slice(&stats.erase_count.to_le_bytes()).ok();
+                buf.extend_from_slice(&stats.write_count.to_le_bytes()).ok();
+                buf.push(stats.active_slot.map_or(0xff, |i| i as u8)).ok();
+                buf.push(stats.slots as u8).ok();
+                buf.push(stats.wear_warning() as u8).ok();
+                buf.extend_from_slice(&stats.profile_erase_count.to_le_bytes()).ok();
+                xfer.accept_with(&buf).ok();
+            }
             Ok(ControlRequest::Read) => {
                 if let Ok(key) = req.value.try_into() {
                     match key {
@@ -293,19 +907,19 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
                             write!(
                                 buf,
                                 "{:.2}W {:.2}V {:.2}A",
-                                self.data.power, self.data.voltage, self.data.current
+                                self.data.readings.power, self.data.readings.voltage, self.data.readings.current
                             )
                             .ok();
                             xfer.accept_with(&buf).ok();
                         }
                         ReadKey::Voltage => {
                             let mut buf = heapless::Vec::<u8, MAX_READ_LENGTH>::new();
-                            write!(buf, "{:.2}V", self.data.voltage).ok();
+                            write!(buf, "{:.2}V", self.data.readings.voltage).ok();
                             xfer.accept_with(&buf).ok();
                         }
                         ReadKey::Current => {
                             let mut buf = heapless::Vec::<u8, MAX_READ_LENGTH>::new();
-                            write!(buf, "{:.2}A", self.data.current).ok();
+                            write!(buf, "{:.2}A", self.data.readings.current).ok();
                             xfer.accept_with(&buf).ok();
                         }
                     }
@@ -332,45 +946,196 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
             _ => return,
         }
 
+        // while a lease is held the state changing requests must carry its token at the end of
+        // the data stage, with the length of the token in the high byte of wIndex. ConfigCommit,
+        // whose wIndex holds the ConfigKey, carries the token alone in its data stage. The data
+        // below is the data stage without the token. ConfigWrite needs the token as well, as
+        // there is a single staging buffer for all the hosts
+        let now = crate::uptime_ms();
+        let (data, token) = match req.request.try_into() {
+            // the clock is shared by all the hosts
+            Ok(ControlRequest::Refresh) | Ok(ControlRequest::Lease) | Ok(ControlRequest::Time) => (xfer.data(), None),
+            Ok(ControlRequest::ConfigCommit) => (&[][..], Some(xfer.data())),
+            _ => match split_token(req, xfer.data()) {
+                Some((data, token)) => (data, Some(token)),
+                None => {
+                    xfer.reject().unwrap();
+                    return;
+                }
+            },
+        };
+        if token.map_or(false, |token| !self.lease.allows(token, now)) {
+            xfer.reject().unwrap();
+            return;
+        }
+        // operations queued by the lease holder can be found with Operation and wValue 0
+        self.submitter = token.filter(|_| self.lease.holder(now).is_some()).and_then(|t| heapless::Vec::from_slice(t).ok());
+
         match req.request.try_into() {
             Ok(ControlRequest::Refresh) => {
-                self.refresh = Some(());
+                // reads are always up to date, kept for older clients
                 xfer.accept().unwrap();
             }
+            Ok(ControlRequest::Lease) => {
+                let duration = data.get(..2).map(|d| u16::from_le_bytes([d[0], d[1]]));
+                let result = match (req.value.try_into(), duration) {
+                    (Ok(LeaseAction::Acquire), Some(d)) => self.lease.acquire(&data[2..], d, now),
+                    (Ok(LeaseAction::Renew), Some(d)) => self.lease.renew(&data[2..], d, now),
+                    (Ok(LeaseAction::Release), _) => self.lease.release(data, now),
+                    _ => Err(LeaseError::Invalid),
+                };
+                if result.is_ok() {
+                    xfer.accept().unwrap();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Time) => {
+                // wValue is the TimeAction, the data stage the epoch time in ms (u64 LE) for Set
+                // and Sync, or the drift correction in ppm (i32 LE) for Drift
+                let epoch_ms = data.try_into().ok().map(u64::from_le_bytes);
+                let ppm = data.try_into().ok().map(i32::from_le_bytes);
+                let accepted = match (req.value.try_into(), epoch_ms, ppm) {
+                    (Ok(TimeAction::Set), Some(t), _) => {
+                        clock::set(t);
+                        true
+                    }
+                    (Ok(TimeAction::Sync), Some(t), _) => clock::sync(t).is_ok(),
+                    (Ok(TimeAction::Drift), _, Some(ppm)) => clock::set_drift(ppm).is_ok(),
+                    _ => false,
+                };
+                if accepted {
+                    xfer.accept().unwrap();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Reboot) => {
+                // the DUT is powered off and the storage disconnected before the reset
+                if let Ok(action) = req.value.try_into() {
+                    self.accept_action(xfer, Action::Reboot(action));
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
             Ok(ControlRequest::Power) => {
                 if let Ok(action) = req.value.try_into() {
-                    self.power = Some(action);
-                    xfer.accept().unwrap();
+                    self.accept_action(xfer, Action::Power(action));
                 } else {
                     xfer.reject().unwrap();
                 }
             }
             Ok(ControlRequest::Storage) => {
                 if let Ok(action) = req.value.try_into() {
-                    self.storage = Some(action);
-                    xfer.accept().unwrap();
+                    self.accept_action(xfer, Action::Storage(action));
                 } else {
                     xfer.reject().unwrap();
                 }
             }
             Ok(ControlRequest::Config) => {
-                if let Ok(key) = req.value.try_into() {
-                    self.config = Some((key, heapless::Vec::from_slice(xfer.data()).unwrap()));
+                match (req.value.try_into(), heapless::Vec::from_slice(data)) {
+                    (Ok(ConfigKey::Json), _) => xfer.reject().unwrap(), // see ConfigWrite
+                    (Ok(key), Ok(value)) if value.len() <= MAX_CONFIG_LENGTH => {
+                        self.accept_action(xfer, Action::Config(key, value));
+                    }
+                    _ => xfer.reject().unwrap(),
+                }
+            }
+            Ok(ControlRequest::Sequence) => {
+                // the data stage carries the sequence, i.e. "aL,rL,w1,rZ", empty to abort
+                // the running one, progress is reported by the Sequence IN request
+                match heapless::Vec::from_slice(data) {
+                    Ok(seq) if sequence::validate(&seq).is_ok() => {
+                        self.accept_action(xfer, Action::Sequence(seq));
+                    }
+                    _ => xfer.reject().unwrap(),
+                }
+            }
+            Ok(ControlRequest::ConsoleWrite) => {
+                // the data stage is sent to the DUT as is, rejected while the previous
+                // write is still waiting for room in the UART queue
+                if self.console_tx.is_empty() && data.len() <= self.console_tx.capacity() {
+                    for b in data {
+                        self.console_tx.push_back(*b).ok();
+                    }
                     xfer.accept().unwrap();
                 } else {
                     xfer.reject().unwrap();
                 }
             }
+            Ok(ControlRequest::ConfigWrite) => {
+                // wValue is the offset of the chunk, writes must start at 0 and be contiguous,
+                // nothing reaches the config until ConfigCommit, which gives the ConfigKey
+                let staged = match self.staged.take() {
+                    _ if req.value == 0 => Some(heapless::Vec::new()),
+                    Some(value) if req.value as usize == value.len() => Some(value),
+                    _ => None, // a bad chunk aborts the transfer
+                };
+                match staged {
+                    Some(mut value) => {
+                        if value.extend_from_slice(data).is_ok() {
+                            self.staged = Some(value);
+                            xfer.accept().unwrap();
+                        } else {
+                            xfer.reject().unwrap();
+                        }
+                    }
+                    None => xfer.reject().unwrap(),
+                }
+            }
+            Ok(ControlRequest::ConfigCommit) => {
+                // wValue is the total length, as a check that no chunk was lost, the
+                // result is reported through ConfigStatus, i.e. InvalidJson for a json blob
+                // that doesn't parse
+                match (chunk_key(req), self.staged.take()) {
+                    (Some(key), Some(value)) if req.value as usize == value.len() => {
+                        self.accept_action(xfer, Action::Config(key, value));
+                    }
+                    _ => xfer.reject().unwrap(),
+                }
+            }
+            Ok(ControlRequest::FactoryReset) => {
+                // the data stage must carry the confirmation token
+                if data == FACTORY_RESET_TOKEN.as_bytes() {
+                    self.accept_action(xfer, Action::FactoryReset);
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Lock) => {
+                if let (Ok(action), Ok(secret)) = (req.value.try_into(), heapless::Vec::from_slice(data)) {
+                    self.accept_action(xfer, Action::Lock(action, secret));
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Stream) => {
+                let rate = match data {
+                    [lo, hi, ..] => u16::from_le_bytes([*lo, *hi]),
+                    _ => DEFAULT_RATE_HZ,
+                };
+                if let Ok(action) = req.value.try_into() {
+                    self.accept_action(xfer, Action::Stream(action, rate));
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Profile) => {
+                let id = req.value as u8;
+                if (req.value as usize) < MAX_PROFILES {
+                    self.accept_action(xfer, Action::Profile(id));
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
             Ok(ControlRequest::Set) => {
                 if let Ok(key) = req.value.try_into() {
-                    if let Some(Ok(state)) = xfer
-                        .data()
+                    if let Some(Ok(state)) = data
                         .first()
                         .cloned()
                         .map(TryInto::<SetPinState>::try_into)
                     {
-                        self.pin = Some((key, state));
-                        xfer.accept().unwrap();
+                        self.accept_action(xfer, Action::Pin(key, state));
                     } else {
                         xfer.reject().unwrap();
                     }
@@ -1,7 +1,16 @@
 
+// the pin driver only builds for the target, PinState and CTLPinsTrait are used by the host tests
+#[cfg(target_os = "none")]
 use stm32f4xx_hal::gpio::{self,DynamicPin};
+#[cfg(target_os = "none")]
+use stm32f4xx_hal::pac;
+#[cfg(target_os = "none")]
 use embedded_hal::digital::OutputPin;
 
+// cycles of cortex_m::asm::delay taking 100ms at 48MHz, calculated experimentally, it would
+// need to be updated for different clock speeds
+pub const DELAY_CYCLES_100MS: u32 = 3_300_000;
+
 // create an enum with 3 possibilities: High, Low, and Floating
 // this is used to set the CTL pins to a specific state
 #[derive(Copy, Clone)]
@@ -11,6 +20,26 @@ pub enum PinState {
     Floating,
 }
 
+impl PinState {
+    // same letters used in the power sequences, h, l or z in any case
+    pub fn from_u8(ch: u8) -> Option<PinState> {
+        match ch.to_ascii_lowercase() {
+            b'h' => Some(PinState::High),
+            b'l' => Some(PinState::Low),
+            b'z' => Some(PinState::Floating),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            PinState::High => b'h',
+            PinState::Low => b'l',
+            PinState::Floating => b'z',
+        }
+    }
+}
+
 // the power_on/power_off sequences processed by _run_sequence
 // have the following format:
 // coma separated orders which could be:
@@ -40,9 +69,24 @@ pub trait CTLPinsTrait {
     fn set_reset(&mut self, state:PinState);
     fn power_on(&mut self, on_seq: &[u8]);
     fn power_off(&mut self, off_seq: &[u8]);
+    // commanded state of reset, a, b, c and d
+    fn get_pins(&self) -> [PinState; 5];
+    // level read back from reset, a, b, c and d, true when high
+    fn sense_pins(&self) -> [bool; 5];
+    fn is_on(&self) -> bool;
+    // number of power sequences run to completion since boot
+    fn sequence_count(&self) -> u32;
 
+    fn set_pins(&mut self, states: &[PinState; 5]) {
+        self.set_reset(states[0]);
+        self.set_ctl_a(states[1]);
+        self.set_ctl_b(states[2]);
+        self.set_ctl_c(states[3]);
+        self.set_ctl_d(states[4]);
+    }
 }
 
+#[cfg(target_os = "none")]
 pub struct CTLPins<PWPin>
 where
     PWPin: OutputPin, {
@@ -58,8 +102,10 @@ where
     stored_reset: PinState,
     power: PWPin,
     on: bool,
+    sequences: u32,
 }
 
+#[cfg(target_os = "none")]
 impl<PWPin> CTLPins<PWPin>
 where
     PWPin: OutputPin,
@@ -75,7 +121,7 @@ where
                                 ctl_c, stored_c: PinState::Floating,
                                 ctl_d, stored_d: PinState::Floating,
                                 reset, stored_reset: PinState::Floating,
-                                power, on: false};
+                                power, on: false, sequences: 0};
         instance.set_ctl_a(PinState::Floating);
         instance.set_ctl_b(PinState::Floating);
         instance.set_ctl_c(PinState::Floating);
@@ -206,15 +252,14 @@ where
             wait = wait * 10 + (ch - b'0') as u32;
             p += 1;
         }
-        // This value has been calculated experimentally, it would need to be
-        // updated for different clock speeds
-        cortex_m::asm::delay(3_300_000 * wait);
+        cortex_m::asm::delay(DELAY_CYCLES_100MS * wait);
         p
     }
 }
 
 // High output state is not ok when the board is not powered on
 // because it will draw power from the output pins into the carried board
+#[cfg(target_os = "none")]
 fn off_tolerant(state: PinState) -> bool {
     match state {
         PinState::Floating => true,
@@ -223,6 +268,7 @@ fn off_tolerant(state: PinState) -> bool {
     }
 }
 
+#[cfg(target_os = "none")]
 impl<PWPin> CTLPinsTrait for CTLPins<PWPin>
 where
     PWPin: OutputPin,
@@ -263,6 +309,25 @@ where
         }
     }
 
+    fn get_pins(&self) -> [PinState; 5] {
+        [self.stored_reset, self.stored_a, self.stored_b, self.stored_c, self.stored_d]
+    }
+
+    fn sense_pins(&self) -> [bool; 5] {
+        // the input data register reflects the pad level in both input and output modes,
+        // reading it has no side effects
+        let idr = unsafe { (*pac::GPIOA::ptr()).idr.read().bits() };
+        [9, 5, 6, 7, 8].map(|n| idr & (1 << n) != 0)
+    }
+
+    fn is_on(&self) -> bool {
+        self.on
+    }
+
+    fn sequence_count(&self) -> u32 {
+        self.sequences
+    }
+
     fn power_on(&mut self, on_seq: &[u8]) {
         self._set_ctl_a(self.stored_a);
         self._set_ctl_b(self.stored_b);
@@ -273,6 +338,7 @@ where
             self.power.set_high().ok();
         } else {
             self._run_sequence(on_seq);
+            self.sequences = self.sequences.wrapping_add(1);
         }
         self.on = true;
     }
@@ -286,6 +352,7 @@ where
         } else {
             self._run_sequence(on_seq);
             self._float_not_off_tolerant();
+            self.sequences = self.sequences.wrapping_add(1);
         }
         self.on = false;
     }
@@ -4,6 +4,10 @@ use stm32f4xx_hal::otg_fs::UsbBusType;
 use usbd_dfu_rt::{DfuRuntimeClass, DfuRuntimeOps};
 use usb_device::class_prelude::*;
 
+use crate::config::ConfigArea;
+use crate::ctlpins::CTLPinsTrait;
+use crate::storage::StorageSwitchTrait;
+
 pub struct DFUBootloader; 
 
 pub type DFUBootloaderRuntime = DfuRuntimeClass<DFUBootloader>;
@@ -16,21 +20,49 @@ impl DfuRuntimeOps for DFUBootloader {
     const WILL_DETACH: bool = true;
 
     fn detach(&mut self) {
-        cortex_m::interrupt::disable();
+        system_reset(true);
+    }
+}
 
-        let cortex = unsafe { cortex_m::Peripherals::steal() };
+// reset the microcontroller, the bootloader stays in DFU mode when it finds the
+// KEY_STAY_IN_BOOT handshake at the start of the RAM
+fn system_reset(stay_in_boot: bool) -> ! {
+    cortex_m::interrupt::disable();
 
-        let p = 0x2000_0000 as *mut u32;
-        unsafe { p.write_volatile(KEY_STAY_IN_BOOT) };
+    let cortex = unsafe { cortex_m::Peripherals::steal() };
 
-        cortex_m::asm::dsb();
-        unsafe {
-            // System reset request
-            cortex.SCB.aircr.modify(|v| 0x05FA_0004 | (v & 0x700));
-        }
-        cortex_m::asm::dsb();
-        loop {}
+    let p = 0x2000_0000 as *mut u32;
+    unsafe { p.write_volatile(if stay_in_boot { KEY_STAY_IN_BOOT } else { 0 }) };
+
+    cortex_m::asm::dsb();
+    unsafe {
+        // System reset request
+        cortex.SCB.aircr.modify(|v| 0x05FA_0004 | (v & 0x700));
     }
+    cortex_m::asm::dsb();
+    loop {}
+}
+
+// Reboot DUTLink, into the DFU bootloader if requested. The reboot runs from reboot_task in
+// main.rs, so the USB interrupt requesting it can finish its request first.
+pub fn request_reboot(bootloader: bool) {
+    crate::app::reboot_task::spawn(bootloader).ok();
+}
+
+// The DUT is powered off first with the configured power_off sequence and its storage is
+// disconnected, so it doesn't keep running with pins and storage left floating while
+// DUTLink restarts.
+pub fn power_off_dut<C, S>(ctlpins: &mut C, storage: &mut S, config: &ConfigArea)
+where
+    C: CTLPinsTrait,
+    S: StorageSwitchTrait,
+{
+    ctlpins.power_off(&config.get().power_off);
+    storage.power_off();
+}
+
+pub fn reset(bootloader: bool) -> ! {
+    system_reset(bootloader)
 }
 
 /// Returns device serial number as hex string slice.
@@ -55,6 +87,27 @@ pub fn get_serial_str() -> &'static str {
     unsafe { str::from_utf8_unchecked(serial) }
 }
 
+/// Returns the product string: "Dutlink" followed by the configured DUT name, if any.
+pub fn get_product_str(name: &[u8]) -> &'static str {
+    const PREFIX: &[u8] = b"Dutlink";
+    static mut PRODUCT: [u8; PREFIX.len() + 1 + 64] = [0; PREFIX.len() + 1 + 64];
+    let product = unsafe { PRODUCT.as_mut() };
+
+    product[..PREFIX.len()].copy_from_slice(PREFIX);
+    let mut len = PREFIX.len();
+    // a name that isn't valid UTF-8 can't go in a string descriptor, keep the plain product
+    match str::from_utf8(name) {
+        Ok(name) if !name.is_empty() && name.len() <= product.len() - len - 1 => {
+            product[len] = b' ';
+            product[len + 1..len + 1 + name.len()].copy_from_slice(name.as_bytes());
+            len += 1 + name.len();
+        }
+        _ => {}
+    }
+
+    unsafe { str::from_utf8_unchecked(&product[..len]) }
+}
+
 /// Return device serial based on U_ID registers.
 fn read_serial() -> u32 {
     let u_id0 = 0x1FFF_7A10 as *const u32;
@@ -0,0 +1,167 @@
+use heapless::Deque;
+
+use crate::clock::{self, Timestamp, TIMESTAMP_SIZE};
+
+// Events reported to the host on the IN endpoint of the control interface, so host
+// daemons can react to changes without polling the status.
+//
+// event packet format, all values little endian:
+//   0 u8  kind: 2, sample packets use 0 and 1, see stream.rs
+//   1 u8  number of events in the packet
+//   2 u8  events lost because the queue was full since the previous packet, saturating
+//   3 events, 11 bytes each:
+//       u8  EventKind
+//       u8  argument, see EventKind
+//       Timestamp of the event, epoch time or uptime, see clock.rs
+
+pub const EVENT_PACKET_KIND: u8 = 2;
+const HEADER_SIZE: usize = 3;
+const EVENT_SIZE: usize = 2 + TIMESTAMP_SIZE;
+const EVENTS_PER_PACKET: usize = 5;
+const QUEUE_EVENTS: usize = 32;
+const PACKET_LENGTH: usize = HEADER_SIZE + EVENTS_PER_PACKET * EVENT_SIZE;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum EventKind {
+    PowerChanged = 0,     // argument: 0 off, 1 on
+    SequenceComplete = 1, // a power sequence finished, argument: power state after it
+    Overcurrent = 2,      // the current went above OVERCURRENT_LIMIT_A
+    ConfigChanged = 3,    // the config was written or erased
+    ButtonPress = 4,      // argument: 0 pressed, 1 held for a factory reset
+    StorageChanged = 5,   // argument: 0 off, 1 host, 2 DUT, as in StorageAction
+}
+
+#[derive(Clone, Copy)]
+struct Event {
+    kind: EventKind,
+    arg: u8,
+    timestamp: Timestamp,
+}
+
+pub struct EventQueue {
+    events: Deque<Event, QUEUE_EVENTS>,
+    lost: u8,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        EventQueue { events: Deque::new(), lost: 0 }
+    }
+
+    // queue an event, dropping the oldest one when full
+    pub fn push(&mut self, kind: EventKind, arg: u8) {
+        let event = Event { kind, arg, timestamp: clock::timestamp() };
+        if self.events.is_full() {
+            self.events.pop_front();
+            self.lost = self.lost.saturating_add(1);
+        }
+        self.events.push_back(event).ok();
+    }
+
+    // build the next packet without consuming the events, see consume
+    pub fn next_packet(&self) -> Option<heapless::Vec<u8, PACKET_LENGTH>> {
+        if self.events.is_empty() {
+            return None;
+        }
+        let count = self.events.len().min(EVENTS_PER_PACKET);
+        let mut buf = heapless::Vec::new();
+        buf.push(EVENT_PACKET_KIND).ok();
+        buf.push(count as u8).ok();
+        buf.push(self.lost).ok();
+        for e in self.events.iter().take(count) {
+            buf.push(e.kind as u8).ok();
+            buf.push(e.arg).ok();
+            buf.extend_from_slice(&e.timestamp.to_bytes()).ok();
+        }
+        Some(buf)
+    }
+
+    // drop the events of a packet once the endpoint accepted it
+    pub fn consume(&mut self, count: usize) {
+        for _ in 0..count {
+            self.events.pop_front();
+        }
+        self.lost = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(packet: &[u8]) -> Vec<u8> {
+        packet[HEADER_SIZE..].chunks(EVENT_SIZE).map(|e| e[0]).collect()
+    }
+
+    #[test]
+    fn empty_queue_has_no_packet() {
+        let mut events = EventQueue::new();
+        assert!(events.next_packet().is_none());
+        events.push(EventKind::PowerChanged, 1);
+        events.consume(1);
+        assert!(events.next_packet().is_none());
+    }
+
+    #[test]
+    fn packet_encoding() {
+        let mut events = EventQueue::new();
+        events.push(EventKind::PowerChanged, 1);
+        events.push(EventKind::StorageChanged, 2);
+        let packet = events.next_packet().unwrap();
+        let time = clock::timestamp().to_bytes();
+        let mut expected = vec![EVENT_PACKET_KIND, 2, 0];
+        expected.extend_from_slice(&[EventKind::PowerChanged as u8, 1]);
+        expected.extend_from_slice(&time);
+        expected.extend_from_slice(&[EventKind::StorageChanged as u8, 2]);
+        expected.extend_from_slice(&time);
+        assert_eq!(&packet[..], &expected[..]);
+    }
+
+    #[test]
+    fn packets_hold_events_in_order_until_consumed() {
+        let mut events = EventQueue::new();
+        for i in 0..EVENTS_PER_PACKET + 2 {
+            events.push(EventKind::ButtonPress, i as u8);
+        }
+        let packet = events.next_packet().unwrap();
+        assert_eq!(packet.len(), PACKET_LENGTH);
+        assert_eq!(packet[1] as usize, EVENTS_PER_PACKET);
+        assert_eq!(packet[HEADER_SIZE + 1], 0);
+        // not consumed yet, the same packet is built again
+        assert_eq!(events.next_packet().unwrap(), packet);
+
+        events.consume(packet[1] as usize);
+        let packet = events.next_packet().unwrap();
+        assert_eq!(packet[1], 2);
+        assert_eq!(packet[HEADER_SIZE + 1] as usize, EVENTS_PER_PACKET);
+    }
+
+    #[test]
+    fn overflow_drops_the_oldest_events() {
+        let mut events = EventQueue::new();
+        for i in 0..QUEUE_EVENTS + 3 {
+            events.push(EventKind::ButtonPress, i as u8);
+        }
+        let packet = events.next_packet().unwrap();
+        assert_eq!(packet[2], 3);
+        assert_eq!(packet[HEADER_SIZE + 1], 3);
+
+        // the lost count is only reported once
+        events.consume(packet[1] as usize);
+        let packet = events.next_packet().unwrap();
+        assert_eq!(packet[2], 0);
+        assert_eq!(packet[HEADER_SIZE + 1] as usize, 3 + EVENTS_PER_PACKET);
+    }
+
+    #[test]
+    fn lost_count_saturates() {
+        let mut events = EventQueue::new();
+        for _ in 0..QUEUE_EVENTS + 300 {
+            events.push(EventKind::Overcurrent, 0);
+        }
+        let packet = events.next_packet().unwrap();
+        assert_eq!(packet[2], u8::MAX);
+        assert_eq!(kinds(&packet), [EventKind::Overcurrent as u8; EVENTS_PER_PACKET]);
+    }
+}
@@ -0,0 +1,314 @@
+// Minimal JSON validator and path query for the json blob stored in the config.
+// It works directly on the stored bytes without allocating, query results are
+// returned as slices of the input holding the raw JSON text of the value.
+//
+// paths are dot separated object keys or array indexes, i.e. "board.soc" or "ports.0.name",
+// keys are compared byte by byte against the raw (still escaped) JSON key
+
+// objects and arrays nested deeper than this are rejected
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JsonError {
+    Syntax(usize),   // unexpected byte or end of input at the given offset
+    TooDeep(usize),  // nesting deeper than MAX_DEPTH at the given offset
+    Trailing(usize), // data after the top level value at the given offset
+}
+
+impl JsonError {
+    pub fn offset(&self) -> usize {
+        match *self {
+            JsonError::Syntax(p) | JsonError::TooDeep(p) | JsonError::Trailing(p) => p,
+        }
+    }
+}
+
+pub fn validate(input: &[u8]) -> Result<(), JsonError> {
+    let end = parse_value(input, skip_ws(input, 0), 0)?;
+    let end = skip_ws(input, end);
+    if end != input.len() {
+        return Err(JsonError::Trailing(end));
+    }
+    Ok(())
+}
+
+pub fn query<'a>(input: &'a [u8], path: &str) -> Result<Option<&'a [u8]>, JsonError> {
+    validate(input)?;
+
+    let mut start = skip_ws(input, 0);
+    for segment in path.split('.').filter(|s| !s.is_empty()) {
+        match find_child(input, start, segment)? {
+            Some(p) => start = p,
+            None => return Ok(None),
+        }
+    }
+    let end = parse_value(input, start, 0)?;
+    Ok(Some(&input[start..end]))
+}
+
+// find the start of the value for a key (objects) or index (arrays) of the value at p
+fn find_child(input: &[u8], p: usize, segment: &str) -> Result<Option<usize>, JsonError> {
+    match input.get(p).copied() {
+        Some(b'{') => {
+            let mut p = skip_ws(input, p + 1);
+            if input.get(p).copied() == Some(b'}') {
+                return Ok(None);
+            }
+            loop {
+                let key_end = parse_string(input, p)?;
+                let key = &input[p + 1..key_end - 1];
+                p = skip_ws(input, expect(input, skip_ws(input, key_end), b':')?);
+                if key == segment.as_bytes() {
+                    return Ok(Some(p));
+                }
+                p = skip_ws(input, parse_value(input, p, 0)?);
+                match input.get(p).copied() {
+                    Some(b',') => p = skip_ws(input, p + 1),
+                    _ => return Ok(None),
+                }
+            }
+        }
+        Some(b'[') => {
+            let index: usize = match segment.parse() {
+                Ok(i) => i,
+                Err(_) => return Ok(None),
+            };
+            let mut p = skip_ws(input, p + 1);
+            if input.get(p).copied() == Some(b']') {
+                return Ok(None);
+            }
+            let mut i = 0;
+            loop {
+                if i == index {
+                    return Ok(Some(p));
+                }
+                p = skip_ws(input, parse_value(input, p, 0)?);
+                match input.get(p).copied() {
+                    Some(b',') => p = skip_ws(input, p + 1),
+                    _ => return Ok(None),
+                }
+                i += 1;
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+// parse the value starting at p, returns the offset right after it
+fn parse_value(input: &[u8], p: usize, depth: usize) -> Result<usize, JsonError> {
+    let container = matches!(input.get(p), Some(b'{') | Some(b'['));
+    if container && depth >= MAX_DEPTH {
+        return Err(JsonError::TooDeep(p));
+    }
+    match input.get(p).copied() {
+        Some(b'{') => parse_object(input, p, depth),
+        Some(b'[') => parse_array(input, p, depth),
+        Some(b'"') => parse_string(input, p),
+        Some(b't') => parse_literal(input, p, b"true"),
+        Some(b'f') => parse_literal(input, p, b"false"),
+        Some(b'n') => parse_literal(input, p, b"null"),
+        Some(b'-') | Some(b'0'..=b'9') => parse_number(input, p),
+        _ => Err(JsonError::Syntax(p)),
+    }
+}
+
+fn parse_object(input: &[u8], p: usize, depth: usize) -> Result<usize, JsonError> {
+    let mut p = skip_ws(input, p + 1);
+    if input.get(p).copied() == Some(b'}') {
+        return Ok(p + 1);
+    }
+    loop {
+        p = skip_ws(input, parse_string(input, p)?);
+        p = skip_ws(input, expect(input, p, b':')?);
+        p = skip_ws(input, parse_value(input, p, depth + 1)?);
+        match input.get(p).copied() {
+            Some(b',') => p = skip_ws(input, p + 1),
+            Some(b'}') => return Ok(p + 1),
+            _ => return Err(JsonError::Syntax(p)),
+        }
+    }
+}
+
+fn parse_array(input: &[u8], p: usize, depth: usize) -> Result<usize, JsonError> {
+    let mut p = skip_ws(input, p + 1);
+    if input.get(p).copied() == Some(b']') {
+        return Ok(p + 1);
+    }
+    loop {
+        p = skip_ws(input, parse_value(input, p, depth + 1)?);
+        match input.get(p).copied() {
+            Some(b',') => p = skip_ws(input, p + 1),
+            Some(b']') => return Ok(p + 1),
+            _ => return Err(JsonError::Syntax(p)),
+        }
+    }
+}
+
+fn parse_string(input: &[u8], p: usize) -> Result<usize, JsonError> {
+    if input.get(p).copied() != Some(b'"') {
+        return Err(JsonError::Syntax(p));
+    }
+    let mut p = p + 1;
+    loop {
+        match input.get(p).copied() {
+            Some(b'"') => return Ok(p + 1),
+            Some(b'\\') => match input.get(p + 1).copied() {
+                Some(b'"') | Some(b'\\') | Some(b'/') | Some(b'b') | Some(b'f') | Some(b'n')
+                | Some(b'r') | Some(b't') => p += 2,
+                Some(b'u') => {
+                    for i in p + 2..p + 6 {
+                        if !input.get(i).is_some_and(|c| c.is_ascii_hexdigit()) {
+                            return Err(JsonError::Syntax(i));
+                        }
+                    }
+                    p += 6;
+                }
+                _ => return Err(JsonError::Syntax(p + 1)),
+            },
+            Some(c) if c >= 0x20 => p += 1,
+            _ => return Err(JsonError::Syntax(p)), // control characters or end of input
+        }
+    }
+}
+
+fn parse_number(input: &[u8], p: usize) -> Result<usize, JsonError> {
+    let mut p = p;
+    if input.get(p).copied() == Some(b'-') {
+        p += 1;
+    }
+    match input.get(p).copied() {
+        Some(b'0') => p += 1,
+        Some(b'1'..=b'9') => p = skip_digits(input, p),
+        _ => return Err(JsonError::Syntax(p)),
+    }
+    if input.get(p).copied() == Some(b'.') {
+        let start = p + 1;
+        p = skip_digits(input, start);
+        if p == start {
+            return Err(JsonError::Syntax(p));
+        }
+    }
+    if let Some(b'e') | Some(b'E') = input.get(p).copied() {
+        p += 1;
+        if let Some(b'+') | Some(b'-') = input.get(p).copied() {
+            p += 1;
+        }
+        let start = p;
+        p = skip_digits(input, start);
+        if p == start {
+            return Err(JsonError::Syntax(p));
+        }
+    }
+    Ok(p)
+}
+
+fn parse_literal(input: &[u8], p: usize, literal: &[u8]) -> Result<usize, JsonError> {
+    if input.get(p..p + literal.len()) == Some(literal) {
+        Ok(p + literal.len())
+    } else {
+        Err(JsonError::Syntax(p))
+    }
+}
+
+fn expect(input: &[u8], p: usize, c: u8) -> Result<usize, JsonError> {
+    if input.get(p).copied() == Some(c) {
+        Ok(p + 1)
+    } else {
+        Err(JsonError::Syntax(p))
+    }
+}
+
+fn skip_digits(input: &[u8], mut p: usize) -> usize {
+    while input.get(p).is_some_and(|c| c.is_ascii_digit()) {
+        p += 1;
+    }
+    p
+}
+
+fn skip_ws(input: &[u8], mut p: usize) -> usize {
+    while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = input.get(p).copied() {
+        p += 1;
+    }
+    p
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get<'a>(input: &'a str, path: &str) -> Option<&'a str> {
+        query(input.as_bytes(), path).unwrap().map(|v| core::str::from_utf8(v).unwrap())
+    }
+
+    #[test]
+    fn valid() {
+        for input in ["0", "-1.5e+3", "true", "null", r#""""#, " [] ", "{}", r#"{"a": [1, {"b": null}], "c": false}"#].iter() {
+            assert_eq!(validate(input.as_bytes()), Ok(()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(validate(br#""a\"b\\c\/\b\f\n\r\t""#), Ok(()));
+        assert_eq!(validate(r#""\u00e9\uABcd é""#.as_bytes()), Ok(()));
+        assert_eq!(validate(br#""\x""#), Err(JsonError::Syntax(2)));
+        assert_eq!(validate(br#""\u12g4""#), Err(JsonError::Syntax(5)));
+        assert_eq!(validate(br#""\u12"#), Err(JsonError::Syntax(5)));
+        assert_eq!(validate(b"\"a\nb\""), Err(JsonError::Syntax(2)));
+    }
+
+    #[test]
+    fn invalid_offsets() {
+        assert_eq!(validate(b""), Err(JsonError::Syntax(0)));
+        assert_eq!(validate(b"  "), Err(JsonError::Syntax(2)));
+        assert_eq!(validate(br#"{"a" 1}"#), Err(JsonError::Syntax(5)));
+        assert_eq!(validate(br#"{"a": 1,}"#), Err(JsonError::Syntax(8)));
+        assert_eq!(validate(b"[1 2]"), Err(JsonError::Syntax(3)));
+        assert_eq!(validate(b"[1, tru]"), Err(JsonError::Syntax(4)));
+        assert_eq!(validate(b"01"), Err(JsonError::Trailing(1)));
+        assert_eq!(validate(b"1."), Err(JsonError::Syntax(2)));
+        assert_eq!(validate(b"1e"), Err(JsonError::Syntax(2)));
+        assert_eq!(validate(b"-"), Err(JsonError::Syntax(1)));
+        assert_eq!(validate(br#""abc"#), Err(JsonError::Syntax(4)));
+        assert_eq!(validate(b"{} {}"), Err(JsonError::Trailing(3)));
+        assert_eq!(JsonError::Trailing(3).offset(), 3);
+    }
+
+    #[test]
+    fn nesting() {
+        let nested = |n: usize| "[".repeat(n) + &"]".repeat(n);
+        assert_eq!(validate(nested(MAX_DEPTH).as_bytes()), Ok(()));
+        assert_eq!(validate(nested(MAX_DEPTH + 1).as_bytes()), Err(JsonError::TooDeep(MAX_DEPTH)));
+        let deepest = "[".repeat(MAX_DEPTH) + "1" + &"]".repeat(MAX_DEPTH);
+        assert_eq!(validate(deepest.as_bytes()), Ok(()));
+        let object = r#"{"a":"#.repeat(MAX_DEPTH + 1) + "1" + &"}".repeat(MAX_DEPTH + 1);
+        assert_eq!(validate(object.as_bytes()), Err(JsonError::TooDeep(5 * MAX_DEPTH)));
+        assert_eq!(get(r#"{"a": {"b": {"c": [1, [2, 3]]}}}"#, "a.b.c.1.0"), Some("2"));
+    }
+
+    #[test]
+    fn paths() {
+        let input = r#" {"board": {"soc": "imx8", "rev": 2}, "ports": [{"name": "eth0"}, {"name": "usb"}], "a\"b": 1} "#;
+        assert_eq!(get(input, ""), Some(input.trim()));
+        assert_eq!(get(input, "board.soc"), Some(r#""imx8""#));
+        assert_eq!(get(input, "board"), Some(r#"{"soc": "imx8", "rev": 2}"#));
+        assert_eq!(get(input, "ports.1.name"), Some(r#""usb""#));
+        // keys are compared still escaped
+        assert_eq!(get(input, r#"a\"b"#), Some("1"));
+        assert_eq!(get(input, r#"a"b"#), None);
+    }
+
+    #[test]
+    fn path_misses() {
+        let input = r#"{"board": {"soc": "imx8"}, "ports": [1, 2], "empty": {}, "none": []}"#;
+        assert_eq!(get(input, "missing"), None);
+        assert_eq!(get(input, "board.rev"), None);
+        assert_eq!(get(input, "board.soc.x"), None);
+        assert_eq!(get(input, "ports.2"), None);
+        assert_eq!(get(input, "ports.x"), None);
+        assert_eq!(get(input, "ports.-1"), None);
+        assert_eq!(get(input, "empty.a"), None);
+        assert_eq!(get(input, "none.0"), None);
+        assert_eq!(query(b"{\"a\": }", "a"), Err(JsonError::Syntax(6)));
+    }
+}
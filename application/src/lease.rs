@@ -0,0 +1,147 @@
+// Exclusive access lease on the control interface, so CI runners sharing a DUTLink don't
+// power cycle each other's DUT. A client acquires the lease with a token of its choice and a
+// duration, and must renew it before it expires. While the lease is held, the state changing
+// control requests are only accepted when they carry the token, at the end of their data
+// stage, see ControlClass::control_out.
+//
+// This protects against accidents, not against malicious clients: the token of the holder is
+// reported to anyone asking, so it can be shown to whoever is waiting for the DUT.
+
+pub const LEASE_TOKEN_LENGTH: usize = 16;
+pub const MAX_LEASE_S: u16 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeaseError {
+    Held,      // held by another client
+    NotHolder, // renew or release without holding the lease
+    Invalid,   // empty or too long token, or a zero duration
+}
+
+pub struct Lease {
+    token: heapless::Vec<u8, LEASE_TOKEN_LENGTH>, // empty while free
+    expires_ms: u64,
+}
+
+impl Lease {
+    pub fn new() -> Self {
+        Lease { token: heapless::Vec::new(), expires_ms: 0 }
+    }
+
+    // token of the current holder, None when free or expired
+    pub fn holder(&self, now_ms: u64) -> Option<&[u8]> {
+        if self.token.is_empty() || now_ms >= self.expires_ms {
+            None
+        } else {
+            Some(&self.token)
+        }
+    }
+
+    pub fn remaining_ms(&self, now_ms: u64) -> u64 {
+        match self.holder(now_ms) {
+            Some(_) => self.expires_ms - now_ms,
+            None => 0,
+        }
+    }
+
+    // true when a request carrying token may change the state of the device
+    pub fn allows(&self, token: &[u8], now_ms: u64) -> bool {
+        self.holder(now_ms).is_none_or(|holder| holder == token)
+    }
+
+    // acquiring a lease already held with the same token renews it
+    pub fn acquire(&mut self, token: &[u8], duration_s: u16, now_ms: u64) -> Result<(), LeaseError> {
+        if !self.allows(token, now_ms) {
+            return Err(LeaseError::Held);
+        }
+        if duration_s == 0 {
+            return Err(LeaseError::Invalid);
+        }
+        self.token = match heapless::Vec::from_slice(token) {
+            Ok(t) if !t.is_empty() => t,
+            _ => return Err(LeaseError::Invalid),
+        };
+        self.expires_ms = now_ms + duration_s.min(MAX_LEASE_S) as u64 * 1000;
+        Ok(())
+    }
+
+    pub fn renew(&mut self, token: &[u8], duration_s: u16, now_ms: u64) -> Result<(), LeaseError> {
+        if self.holder(now_ms) != Some(token) {
+            return Err(LeaseError::NotHolder);
+        }
+        self.acquire(token, duration_s, now_ms)
+    }
+
+    pub fn release(&mut self, token: &[u8], now_ms: u64) -> Result<(), LeaseError> {
+        if self.holder(now_ms) != Some(token) {
+            return Err(LeaseError::NotHolder);
+        }
+        self.token.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_lease_allows_anyone() {
+        let lease = Lease::new();
+        assert_eq!(lease.holder(0), None);
+        assert_eq!(lease.remaining_ms(0), 0);
+        assert!(lease.allows(b"", 0));
+        assert!(lease.allows(b"ci-1", 0));
+    }
+
+    #[test]
+    fn held_lease_allows_the_holder_only() {
+        let mut lease = Lease::new();
+        assert_eq!(lease.acquire(b"ci-1", 10, 1000), Ok(()));
+        assert_eq!(lease.holder(1000), Some(&b"ci-1"[..]));
+        assert_eq!(lease.remaining_ms(5000), 6000);
+        assert!(lease.allows(b"ci-1", 5000));
+        assert!(!lease.allows(b"ci-2", 5000));
+        assert!(!lease.allows(b"", 5000));
+        assert_eq!(lease.acquire(b"ci-2", 10, 5000), Err(LeaseError::Held));
+    }
+
+    #[test]
+    fn lease_expires() {
+        let mut lease = Lease::new();
+        lease.acquire(b"ci-1", 10, 1000).unwrap();
+        assert_eq!(lease.holder(10_999), Some(&b"ci-1"[..]));
+        assert_eq!(lease.holder(11_000), None);
+        assert!(lease.allows(b"ci-2", 11_000));
+        assert_eq!(lease.renew(b"ci-1", 10, 11_000), Err(LeaseError::NotHolder));
+        assert_eq!(lease.acquire(b"ci-2", 10, 11_000), Ok(()));
+    }
+
+    #[test]
+    fn renew_and_release() {
+        let mut lease = Lease::new();
+        lease.acquire(b"ci-1", 10, 0).unwrap();
+        assert_eq!(lease.renew(b"ci-2", 10, 5000), Err(LeaseError::NotHolder));
+        assert_eq!(lease.renew(b"ci-1", 10, 5000), Ok(()));
+        assert_eq!(lease.remaining_ms(5000), 10_000);
+        // acquiring again with the same token renews too
+        assert_eq!(lease.acquire(b"ci-1", 20, 6000), Ok(()));
+        assert_eq!(lease.remaining_ms(6000), 20_000);
+
+        assert_eq!(lease.release(b"ci-2", 7000), Err(LeaseError::NotHolder));
+        assert_eq!(lease.release(b"ci-1", 7000), Ok(()));
+        assert_eq!(lease.holder(7000), None);
+        assert_eq!(lease.release(b"ci-1", 7000), Err(LeaseError::NotHolder));
+    }
+
+    #[test]
+    fn invalid_leases() {
+        let mut lease = Lease::new();
+        assert_eq!(lease.acquire(b"", 10, 0), Err(LeaseError::Invalid));
+        assert_eq!(lease.acquire(b"ci-1", 0, 0), Err(LeaseError::Invalid));
+        assert_eq!(lease.acquire(&[b'x'; LEASE_TOKEN_LENGTH + 1], 10, 0), Err(LeaseError::Invalid));
+        assert_eq!(lease.holder(0), None);
+
+        assert_eq!(lease.acquire(&[b'x'; LEASE_TOKEN_LENGTH], MAX_LEASE_S + 1, 0), Ok(()));
+        assert_eq!(lease.remaining_ms(0), MAX_LEASE_S as u64 * 1000);
+    }
+}
@@ -14,6 +14,25 @@ mod powermeter;
 mod filter;
 mod version;
 mod config;
+mod button;
+mod json;
+mod profiles;
+mod status;
+mod stream;
+mod events;
+mod sequence;
+mod console;
+mod lease;
+mod msos;
+mod clock;
+mod reply;
+mod script;
+mod tokenizer;
+
+// milliseconds since boot
+pub fn uptime_ms() -> u64 {
+    app::monotonics::now().duration_since_epoch().to_millis()
+}
 
 // dispatchers are free Hardware IRQs we don't use that rtic will use to dispatch
 // software tasks, we are not using EXT interrupts, so we can use those
@@ -38,32 +57,43 @@ mod app {
     use usb_device::{class_prelude::*, prelude::*};
 
     use usbd_serial::SerialPort;
+    use systick_monotonic::{ExtU64, Systick};
 
-    use crate::{control::ControlClass, dfu::{get_serial_str, new_dfu_bootloader, DFUBootloaderRuntime}};
+    use crate::{control::ControlClass, dfu::{get_product_str, get_serial_str, new_dfu_bootloader, DFUBootloaderRuntime}};
     use crate::storage::*;
     use crate::usbserial::*;
     use crate::shell;
-    use crate::ctlpins;
+    use crate::ctlpins::{self, CTLPinsTrait};
     use crate::powermeter::*;
     use crate::version;
     use crate::config::*;
+    use crate::button::*;
+    use crate::stream::{Decimator, SampleStream, DEFAULT_RATE_HZ};
+    use crate::events::{EventKind, EventQueue};
+    use crate::sequence::Sequencer;
+    use crate::script::{Batch, BootLog};
 
     type LedCmdType = gpio::PC15<Output<PushPull>>;
     type StorageSwitchType = StorageSwitch<gpio::PA15<Output<PushPull>>, gpio::PB3<Output<PushPull>>,
                                            gpio::PB5<Output<PushPull>>, gpio::PB4<Output<PushPull>>>;
     type CTLPinsType = ctlpins::CTLPins<gpio::PA4<Output<PushPull>>>;
+    type ButtonType = Button<gpio::PA0<Input>>;
     type DMATransfer = Transfer<Stream0<DMA2>, 0, Adc<ADC1>, PeripheralToMemory, &'static mut [u16; 2]>;
 
+    #[monotonic(binds = SysTick, default = true)]
+    type Mono = Systick<1000>; // 1ms resolution
+
     const DUT_BUF_SIZE: usize = 1024;
     // Resources shared between tasks
     #[shared]
     struct Shared {
         timer: timer::CounterMs<pac::TIM2>,
+        adc_timer: timer::CounterHz<pac::TIM3>,
         usb_dev: UsbDevice<'static, UsbBusType>,
         shell: shell::ShellType,
         shell_status: shell::ShellStatus,
         dfu: DFUBootloaderRuntime,
-        ctl: ControlClass,
+        ctl: ControlClass<'static, UsbBusType>,
 
         led_tx: gpio::PC13<Output<PushPull>>,
         led_rx: gpio::PC14<Output<PushPull>>,
@@ -76,14 +106,19 @@ mod app {
         ctl_pins: CTLPinsType,
 
         power_meter: MAVPowerMeter,
+        readings: PowerReadings,
+        stream: SampleStream,
+        events: EventQueue,
+        sequencer: Sequencer,
 
         config: ConfigArea,
+        boot_log: BootLog,
     }
 
     // Local resources to specific tasks (cannot be shared)
     #[local]
     struct Local {
-        _button: gpio::PA0<Input>,
+        button: ButtonType,
         usart_rx: Rx<pac::USART1>,
         usart_tx: Tx<pac::USART1>,
         to_dut_serial: Producer<'static, u8, DUT_BUF_SIZE>,          // queue of characters to send to the DUT
@@ -107,6 +142,8 @@ mod app {
             .require_pll48clk()
             .freeze();
 
+        let mono = Systick::new(ctx.core.SYST, clocks.sysclk().raw());
+
         // Configure the on-board LED (PC13, blue)
         let gpioa = dp.GPIOA.split();
         let gpiob = dp.GPIOB.split();
@@ -120,9 +157,9 @@ mod app {
         led_rx.set_high();
         led_cmd.set_high();
 
-        let _button = gpioa.pa0.into_pull_up_input();
+        let button = Button::new(gpioa.pa0.into_pull_up_input());
 
-        let ctl_pins = ctlpins::CTLPins::new(gpioa.pa5.into_dynamic(),          // ctl_a
+        let mut ctl_pins = ctlpins::CTLPins::new(gpioa.pa5.into_dynamic(),          // ctl_a
                                              gpioa.pa6.into_dynamic(),          // ctl_b
                                              gpioa.pa7.into_dynamic(),          // ctl_c
                                              gpioa.pa8.into_dynamic(),          // ctl_d
@@ -183,6 +220,12 @@ mod app {
         timer.start(10.millis()).unwrap(); //100Hz
         timer.listen(timer::Event::Update);
 
+        // the ADC conversions are triggered by their own timer, so the rate can be raised while
+        // streaming samples
+        let mut adc_timer = dp.TIM3.counter_hz(&clocks);
+        adc_timer.start((DEFAULT_RATE_HZ as u32).Hz()).unwrap();
+        adc_timer.listen(timer::Event::Update);
+
         // Pull the D+ pin down to send a RESET condition to the USB bus.
         let mut usb_dp = gpioa.pa12.into_push_pull_output();
         usb_dp.set_low();
@@ -205,6 +248,10 @@ mod app {
 
         serial1.reset();
 
+        let config = ConfigArea::new(stm32f4xx_hal::flash::LockedFlash::new(dp.FLASH));
+
+        // the product string carries the DUT name, so the device can be told apart in lsusb,
+        // a new name shows up after the next reboot
         let usb_dev = UsbDeviceBuilder::new(
             unsafe { USB_BUS.as_ref().unwrap() },
             UsbVidPid(0x2b23, 0x1012),
@@ -212,30 +259,45 @@ mod app {
         .strings(&[
             StringDescriptors::default()
             .manufacturer("Red Hat Inc.")
-            .product("Dutlink")
+            .product(get_product_str(value_bytes(&config.get().name)))
             .serial_number(get_serial_str())
         ]).unwrap()
         .device_release(version::usb_version_bcd_device())
+        .usb_rev(UsbRev::Usb210) // Windows only reads the BOS descriptor of USB 2.1 devices
         .self_powered(false)
         .max_power(250).unwrap()
         .max_packet_size_0(64).unwrap()
         .build();
 
          let shell = shell::new(serial1);
-         let shell_status = shell::ShellStatus{
+         let mut shell_status = shell::ShellStatus{
              monitor_enabled: false,
              meter_enabled: false,
-             console_mode: true,};
+             console_mode: true,
+             json: false,
+             batch: Batch::new(),};
 
 
         let (to_dut_serial, to_dut_serial_consumer) = ctx.local.q_to_dut.split();
         let (to_host_serial, to_host_serial_consumer) = ctx.local.q_from_dut.split();
 
-        let config = ConfigArea::new(stm32f4xx_hal::flash::LockedFlash::new(dp.FLASH));
+        // restore the pin defaults of the DUT profile in use
+        if let Some(profile) = config.active_profile() {
+            ctl_pins.set_pins(&profile.pins());
+        }
+
+        // the boot script starts from the USB task once the button had a chance to skip it
+        let mut boot_log = BootLog::new();
+        match core::str::from_utf8(config.autoexec()) {
+            Ok("") => {},
+            Ok(script) => { shell_status.batch.boot(script); },
+            Err(_) => { write!(boot_log, "Boot script is not valid UTF-8, not run").ok(); },
+        }
 
         (
             Shared {
                 timer,
+                adc_timer,
                 usb_dev,
                 shell,
                 shell_status,
@@ -248,10 +310,15 @@ mod app {
                 adc_dma_transfer,
                 ctl_pins,
                 power_meter,
+                readings: PowerReadings::new(),
+                stream: SampleStream::new(),
+                events: EventQueue::new(),
+                sequencer: Sequencer::new(),
                 config,
+                boot_log,
             },
             Local {
-                _button,
+                button,
                 usart_tx,
                 usart_rx,
                 to_dut_serial,
@@ -262,22 +329,24 @@ mod app {
             },
             // Move the monotonic timer to the RTIC run-time, this enables
             // scheduling
-            init::Monotonics(),
+            init::Monotonics(mono),
         )
     }
 
-    #[task(binds = USART1, priority=1, local = [usart_rx, to_host_serial], shared = [shell_status, led_rx])]
+    #[task(binds = USART1, priority=1, local = [usart_rx, to_host_serial], shared = [shell_status, led_rx, ctl])]
     fn usart_task(cx: usart_task::Context){
         let usart_rx = cx.local.usart_rx;
         let shell_status = cx.shared.shell_status;
         let led_rx = cx.shared.led_rx;
+        let ctl = cx.shared.ctl;
         let to_host_serial = cx.local.to_host_serial;
 
-        (shell_status, led_rx).lock(|shell_status, led_rx| {
+        (shell_status, led_rx, ctl).lock(|shell_status, led_rx, ctl| {
             while usart_rx.is_rx_not_empty() {
                 led_rx.set_low();
                 match usart_rx.read() {
                     Ok(b) => {
+                        ctl.capture_console(b);
                         if shell_status.console_mode || shell_status.monitor_enabled {
                             to_host_serial.enqueue(b).ok(); // this could over-run but it's ok the only solution would be a bigger buffer
                         }
@@ -320,8 +389,9 @@ mod app {
                             if count >= buf.len() {
                                 break;
                             }
-                            // check if we need to add power readings after the line break
-                            if shell_status.meter_enabled && c == 0x0d {
+                            // check if we need to add power readings after the line break,
+                            // not in json mode where they would break the JSON lines
+                            if shell_status.meter_enabled && !shell_status.json && c == 0x0d {
                                 let mut af = ArrForm::<64>::new();
                                 power_meter.write_trace(&mut af);
 
@@ -343,7 +413,7 @@ mod app {
         }
     }
 
-    #[task(binds = OTG_FS, shared = [usb_dev, shell, shell_status, dfu, ctl, led_cmd, storage, ctl_pins, power_meter, config], local=[esc_cnt:u8 = 0, to_dut_serial])]
+    #[task(binds = OTG_FS, shared = [usb_dev, shell, shell_status, dfu, ctl, led_cmd, storage, ctl_pins, power_meter, readings, stream, events, sequencer, config, boot_log], local=[esc_cnt:u8 = 0, to_dut_serial])]
     fn usb_task(mut cx: usb_task::Context) {
         let usb_dev         = &mut cx.shared.usb_dev;
         let shell           = &mut cx.shared.shell;
@@ -357,17 +427,46 @@ mod app {
         let esc_cnt         = cx.local.esc_cnt;
         let ctl_pins        = &mut cx.shared.ctl_pins;
         let power_meter     = &mut cx.shared.power_meter;
+        let stream          = &mut cx.shared.stream;
+        let events          = &mut cx.shared.events;
+        let sequencer       = &mut cx.shared.sequencer;
         let config          = &mut cx.shared.config;
+        let boot_log        = &mut cx.shared.boot_log;
+
+        let readings = cx.shared.readings.lock(|readings| *readings);
 
         (usb_dev, dfu, ctl, shell, shell_status, led_cmd, storage, ctl_pins, power_meter, config).lock(
             |usb_dev, dfu, ctl, shell, shell_status, led_cmd, storage, ctl_pins, power_meter, config| {
             let serial1 = shell.get_serial_mut();
 
-            if !usb_dev.poll(&mut [serial1, dfu, ctl]) {
+            // other tasks pend this interrupt when new samples or events are waiting
+            (&mut *stream, &mut *events, &mut *sequencer).lock(|stream, events, sequencer| {
+                ctl.update(config, ctl_pins, storage, &readings, stream, sequencer);
+                ctl.watch(config, ctl_pins, storage, events);
+                ctl.write_in(stream, events);
+            });
+
+            // a sleeping shell batch can be due without any USB traffic
+            if !usb_dev.poll(&mut [serial1, dfu, ctl]) && !shell_status.batch.is_due(crate::uptime_ms()) {
                 return;
             }
 
-            ctl.post_poll(config, ctl_pins, storage, power_meter);
+            (&mut *stream, &mut *sequencer).lock(|stream, sequencer| {
+                ctl.post_poll(config, ctl_pins, storage, stream, sequencer)
+            });
+
+            // bytes written to the DUT through the control interface, the idle task interprets
+            // backslash escapes outside console mode, so those need escaping to go through as is
+            let escape = !shell_status.console_mode;
+            ctl.write_console(|b| {
+                if escape && b == b'\\' {
+                    if to_dut_serial.capacity() - to_dut_serial.len() < 2 {
+                        return false;
+                    }
+                    to_dut_serial.enqueue(b).ok();
+                }
+                to_dut_serial.enqueue(b).is_ok()
+            });
 
             let available_to_dut = to_dut_serial.capacity()-to_dut_serial.len();
 
@@ -389,10 +488,7 @@ mod app {
                             if *c == 0x02 { // CTRL+B
                                 *esc_cnt = *esc_cnt + 1;
                                 if *esc_cnt == 5 {
-                                    shell_status.console_mode = false;
-                                    shell_status.monitor_enabled = false;
-                                    shell.write_str("\r\nExiting console mode\r\n").ok();
-                                    shell.write_str(shell::SHELL_PROMPT).ok();
+                                    shell::exit_console_mode(shell, shell_status);
                                     *esc_cnt = 0;
                                 }
                             } else {
@@ -403,34 +499,114 @@ mod app {
                     Err(_e) => {
                     }
                 }
+                boot_log.lock(|boot_log| {
+                    shell::handle_boot_script(shell, shell_status, storage, ctl_pins, &mut send_to_dut, power_meter, config, ctl.lease(), ctl.status(), boot_log);
+                });
             } else {
-                shell::handle_shell_commands(shell, shell_status, led_cmd, storage, ctl_pins, &mut send_to_dut, power_meter, config);
+                boot_log.lock(|boot_log| {
+                    shell::handle_shell_commands(shell, shell_status, led_cmd, storage, ctl_pins, &mut send_to_dut, power_meter, config, ctl.lease(), ctl.status(), boot_log);
+                });
             }
+
+            (&mut *stream, &mut *events).lock(|stream, events| {
+                ctl.watch(config, ctl_pins, storage, events);
+                ctl.write_in(stream, events);
+            });
         });
     }
 
-    #[task(binds = TIM2, shared=[timer, dfu,  led_rx, led_tx, led_cmd, adc_dma_transfer])]
+    #[task(binds = TIM2, shared=[timer, dfu,  led_rx, led_tx, led_cmd, events, sequencer, ctl_pins, shell_status, boot_log], local=[button])]
     fn periodic_10ms(mut ctx: periodic_10ms::Context) {
 
         ctx.shared.dfu.lock(|dfu| dfu.tick(10));
 
-        // clear all leds set in other tasts
-        ctx.shared.led_rx.lock(|led_rx| led_rx.set_high());
-        ctx.shared.led_tx.lock(|led_tx| led_tx.set_high());
-        ctx.shared.led_cmd.lock(|led_cmd| led_cmd.set_high());
+        // the shell resumes sleeping batches from the USB task
+        if ctx.shared.shell_status.lock(|shell_status| shell_status.batch.is_due(crate::uptime_ms())) {
+            rtic::pend(pac::Interrupt::OTG_FS);
+        }
+
+        (&mut ctx.shared.sequencer, &mut ctx.shared.ctl_pins).lock(|sequencer, ctl_pins| {
+            sequencer.tick(ctl_pins, 10);
+        });
+
+        let button = ctx.local.button;
+        match button.tick(10) {
+            ButtonEvent::Pressed => {
+                ctx.shared.events.lock(|events| events.push(EventKind::ButtonPress, 0));
+                (&mut ctx.shared.shell_status, &mut ctx.shared.boot_log).lock(|shell_status, boot_log| {
+                    shell::stop_boot_script(shell_status, boot_log);
+                });
+                rtic::pend(pac::Interrupt::OTG_FS);
+            },
+            ButtonEvent::FactoryReset => {
+                ctx.shared.events.lock(|events| events.push(EventKind::ButtonPress, 1));
+                factory_reset_task::spawn().ok();
+                rtic::pend(pac::Interrupt::OTG_FS);
+            },
+            ButtonEvent::None => {},
+        }
 
+        // clear all leds set in other tasts, unless a button gesture is giving feedback
+        if button.feedback() {
+            ctx.shared.led_rx.lock(|led_rx| led_rx.set_low());
+            ctx.shared.led_tx.lock(|led_tx| led_tx.set_low());
+            ctx.shared.led_cmd.lock(|led_cmd| led_cmd.set_low());
+        } else {
+            ctx.shared.led_rx.lock(|led_rx| led_rx.set_high());
+            ctx.shared.led_tx.lock(|led_tx| led_tx.set_high());
+            ctx.shared.led_cmd.lock(|led_cmd| led_cmd.set_high());
+        }
+
+        ctx.shared
+            .timer
+            .lock(|tim| tim.clear_flags(timer::Flag::Update));
+    }
+
+    // reboot requested by the shell or the control interface, see dfu::request_reboot
+    #[task(shared=[ctl_pins, storage, config])]
+    fn reboot_task(cx: reboot_task::Context, bootloader: bool) {
+        (cx.shared.ctl_pins, cx.shared.storage, cx.shared.config).lock(|ctl_pins, storage, config| {
+            crate::dfu::power_off_dut(ctl_pins, storage, config);
+        });
+        // time for the USB hardware to send what is already queued, like the status stage
+        // of the control request or the reply of the shell command
+        reset_task::spawn_after(100.millis(), bootloader).ok();
+    }
+
+    #[task]
+    fn reset_task(_cx: reset_task::Context, bootloader: bool) {
+        crate::dfu::reset(bootloader)
+    }
+
+    // erasing the config and profile sectors takes long, so the factory reset of the button
+    // runs here rather than in the 10ms tick, the result is reported like a config write
+    #[task(shared=[config, ctl])]
+    fn factory_reset_task(mut cx: factory_reset_task::Context) {
+        // holding the button needs physical access, so it overrides a config lock
+        let result = cx.shared.config.lock(|config| config.force_factory_reset());
+        cx.shared.ctl.lock(|ctl| ctl.report_config_result(result));
+        rtic::pend(pac::Interrupt::OTG_FS);
+    }
+
+    #[task(binds = TIM3, shared=[adc_timer, adc_dma_transfer, stream], local=[rate: u16 = DEFAULT_RATE_HZ])]
+    fn adc_trigger(mut ctx: adc_trigger::Context) {
         ctx.shared.adc_dma_transfer.lock(|transfer| {
             transfer.start(|adc| {
                 adc.start_conversion();
             });
         });
 
-        ctx.shared
-            .timer
-            .lock(|tim| tim.clear_flags(timer::Flag::Update));
+        let rate = ctx.shared.stream.lock(|stream| stream.adc_rate_hz());
+        ctx.shared.adc_timer.lock(|tim| {
+            tim.clear_flags(timer::Flag::Update);
+            if rate != *ctx.local.rate {
+                tim.start((rate as u32).Hz()).ok();
+                *ctx.local.rate = rate;
+            }
+        });
     }
 
-    #[task(binds = DMA2_STREAM0, shared=[adc_dma_transfer, power_meter], local=[adc_buffer])]
+    #[task(binds = DMA2_STREAM0, shared=[adc_dma_transfer, power_meter, readings, stream, events], local=[adc_buffer, meter_decimator: Decimator = Decimator::new(), overcurrent: bool = false])]
     fn adc_dma(mut cx:adc_dma::Context){
         let adc_dma_transfer = &mut cx.shared.adc_dma_transfer;
         let adc_buffer = &mut cx.local.adc_buffer;
@@ -451,23 +627,38 @@ mod app {
         // leave the previous buffer ready again for next transfer
         *cx.local.adc_buffer = Some(buffer);
 
-        // calculate current in amps
-        let current_V = (current_raw as f32 - 2048.0) * 3.3 / 4096.0;
-        let current_A = -current_V / 0.264;
+        let (streaming, adc_rate) = cx.shared.stream.lock(|stream| {
+            stream.push(current_raw, vout_raw, crate::clock::timestamp());
+            (stream.is_enabled(), stream.adc_rate_hz())
+        });
+        if streaming {
+            rtic::pend(pac::Interrupt::OTG_FS);
+        }
+
+        // the moving average expects DEFAULT_RATE_HZ samples, whatever the rate of the ADC
+        if !cx.local.meter_decimator.take(DEFAULT_RATE_HZ, adc_rate) {
+            return;
+        }
 
-        // calculate vin voltage in volts
-        // we get vout from the voltage divider, in 12 bits, 3.3V is 4096
-        let vout_sense_V = (vout_raw as f32) * 3.3 / 4096.0;
-        // we do the reverse calculation to figure out the input voltage
-        let R8 = 2400.0; // R8 is the top resistor in the voltage divider
-        let R9 = 470.0; // R9 is the bottom resistor in the voltage divider
-        let vin = vout_sense_V * (R8 + R9) / R9;
+        let current = current_from_raw(current_raw);
+        let vin = voltage_from_raw(vout_raw);
 
-        power_meter.lock(|power_meter| {
+        let latest = power_meter.lock(|power_meter| {
             power_meter.feed_voltage(vin);
-            power_meter.feed_current(current_A);
+            power_meter.feed_current(current);
+            power_meter.readings()
         });
 
+        // publish the averaged readings for the control interface
+        cx.shared.readings.lock(|readings| *readings = latest);
+
+        let overcurrent = latest.current > OVERCURRENT_LIMIT_A;
+        if overcurrent && !*cx.local.overcurrent {
+            cx.shared.events.lock(|events| events.push(EventKind::Overcurrent, 0));
+            rtic::pend(pac::Interrupt::OTG_FS);
+        }
+        *cx.local.overcurrent = overcurrent;
+
     }
 
 
@@ -0,0 +1,150 @@
+// Microsoft OS 2.0 descriptors, so Windows binds WinUSB to the control interface without
+// Zadig or a manual driver install.
+//
+// Windows reads the BOS descriptor of USB 2.1 devices, finds the MS OS 2.0 platform capability
+// in it, and asks for the descriptor set with a vendor request to the device: bRequest is
+// VENDOR_CODE and wIndex is DESCRIPTOR_INDEX. The set only holds a function subset for the
+// control interface, the CDC and DFU interfaces keep their usual drivers.
+//
+// descriptor set layout, all values little endian:
+//   set header          wLength, wDescriptorType 0, dwWindowsVersion, wTotalLength
+//   configuration subset wLength, wDescriptorType 1, bConfigurationValue 0, bReserved, wTotalLength
+//   function subset     wLength, wDescriptorType 2, bFirstInterface, bReserved, wSubsetLength
+//   compatible id       wLength, wDescriptorType 3, "WINUSB\0\0", 8 bytes of sub compatible id
+//   registry property   wLength, wDescriptorType 4, wPropertyDataType 7 (REG_MULTI_SZ),
+//                       wPropertyNameLength, "DeviceInterfaceGUIDs" (UTF-16, NUL terminated),
+//                       wPropertyDataLength, INTERFACE_GUID (UTF-16, double NUL terminated)
+
+pub const VENDOR_CODE: u8 = 0x20;
+pub const DESCRIPTOR_INDEX: u16 = 7;
+pub const PLATFORM_CAPABILITY: u8 = 0x05;
+
+const WINDOWS_VERSION: u32 = 0x0603_0000; // Windows 8.1, the first one reading these descriptors
+const PLATFORM_UUID: [u8; 16] = [
+    // D8DD60DF-4589-4CC7-9CD2-659D9E648A9F, the first three fields are little endian
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+// lets applications find the control interface through SetupAPI
+const PROPERTY_NAME: &str = "DeviceInterfaceGUIDs";
+const INTERFACE_GUID: &str = "{8b5a94c3-7f2e-4f6b-9d1a-3c6e0b2f7a51}";
+
+const SET_HEADER_LENGTH: usize = 10;
+const CONFIGURATION_SUBSET_LENGTH: usize = 8;
+const FUNCTION_SUBSET_LENGTH: usize = 8;
+const COMPATIBLE_ID_LENGTH: usize = 20;
+const PROPERTY_NAME_LENGTH: usize = (PROPERTY_NAME.len() + 1) * 2;
+const PROPERTY_DATA_LENGTH: usize = (INTERFACE_GUID.len() + 2) * 2;
+const PROPERTY_LENGTH: usize = 10 + PROPERTY_NAME_LENGTH + PROPERTY_DATA_LENGTH;
+const FUNCTION_LENGTH: usize = FUNCTION_SUBSET_LENGTH + COMPATIBLE_ID_LENGTH + PROPERTY_LENGTH;
+const CONFIGURATION_LENGTH: usize = CONFIGURATION_SUBSET_LENGTH + FUNCTION_LENGTH;
+pub const SET_LENGTH: usize = SET_HEADER_LENGTH + CONFIGURATION_LENGTH;
+
+// body of the platform capability, BosWriter adds bLength, bDescriptorType and bDevCapabilityType
+pub fn platform_capability() -> heapless::Vec<u8, 25> {
+    let mut buf = heapless::Vec::<u8, 25>::new();
+    buf.push(0).ok(); // bReserved
+    buf.extend_from_slice(&PLATFORM_UUID).ok();
+    buf.extend_from_slice(&WINDOWS_VERSION.to_le_bytes()).ok();
+    buf.extend_from_slice(&(SET_LENGTH as u16).to_le_bytes()).ok();
+    buf.push(VENDOR_CODE).ok();
+    buf.push(0).ok(); // bAltEnumCode, no alternate enumeration
+    buf
+}
+
+pub fn descriptor_set(first_interface: u8) -> heapless::Vec<u8, SET_LENGTH> {
+    let mut buf = heapless::Vec::<u8, SET_LENGTH>::new();
+
+    header(&mut buf, SET_HEADER_LENGTH, 0x00);
+    buf.extend_from_slice(&WINDOWS_VERSION.to_le_bytes()).ok();
+    buf.extend_from_slice(&(SET_LENGTH as u16).to_le_bytes()).ok();
+
+    header(&mut buf, CONFIGURATION_SUBSET_LENGTH, 0x01);
+    buf.extend_from_slice(&[0, 0]).ok();
+    buf.extend_from_slice(&(CONFIGURATION_LENGTH as u16).to_le_bytes()).ok();
+
+    header(&mut buf, FUNCTION_SUBSET_LENGTH, 0x02);
+    buf.extend_from_slice(&[first_interface, 0]).ok();
+    buf.extend_from_slice(&(FUNCTION_LENGTH as u16).to_le_bytes()).ok();
+
+    header(&mut buf, COMPATIBLE_ID_LENGTH, 0x03);
+    buf.extend_from_slice(b"WINUSB\0\0").ok();
+    buf.extend_from_slice(&[0; 8]).ok();
+
+    header(&mut buf, PROPERTY_LENGTH, 0x04);
+    buf.extend_from_slice(&7u16.to_le_bytes()).ok();
+    buf.extend_from_slice(&(PROPERTY_NAME_LENGTH as u16).to_le_bytes()).ok();
+    utf16(&mut buf, PROPERTY_NAME, 1);
+    buf.extend_from_slice(&(PROPERTY_DATA_LENGTH as u16).to_le_bytes()).ok();
+    utf16(&mut buf, INTERFACE_GUID, 2);
+
+    buf
+}
+
+fn header(buf: &mut heapless::Vec<u8, SET_LENGTH>, length: usize, descriptor_type: u16) {
+    buf.extend_from_slice(&(length as u16).to_le_bytes()).ok();
+    buf.extend_from_slice(&descriptor_type.to_le_bytes()).ok();
+}
+
+// ASCII only, followed by nuls NUL characters
+fn utf16(buf: &mut heapless::Vec<u8, SET_LENGTH>, s: &str, nuls: usize) {
+    for c in s.bytes() {
+        buf.extend_from_slice(&[c, 0]).ok();
+    }
+    for _ in 0..nuls {
+        buf.extend_from_slice(&[0, 0]).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16le(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn platform_capability_bytes() {
+        // as written by BosWriter: bLength, BOS device capability, platform capability type
+        let mut capability = vec![28, 0x10, PLATFORM_CAPABILITY];
+        capability.extend_from_slice(&platform_capability());
+        assert_eq!(capability, [
+            28, 0x10, 0x05, 0x00,
+            0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+            0x00, 0x00, 0x03, 0x06, // dwWindowsVersion
+            178, 0x00,              // wMSOSDescriptorSetTotalLength
+            VENDOR_CODE, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn descriptor_set_bytes() {
+        let set = descriptor_set(3);
+        assert_eq!(set.len(), 178);
+        assert_eq!(SET_LENGTH, 178);
+
+        assert_eq!(set[0..10], [10, 0, 0x00, 0, 0x00, 0x00, 0x03, 0x06, 178, 0]);
+        assert_eq!(set[10..18], [8, 0, 0x01, 0, 0, 0, 168, 0]);
+        assert_eq!(set[18..26], [8, 0, 0x02, 0, 3, 0, 160, 0]);
+        assert_eq!(set[26..30], [20, 0, 0x03, 0]);
+        assert_eq!(&set[30..38], b"WINUSB\0\0");
+        assert_eq!(set[38..46], [0; 8]);
+        assert_eq!(set[46..54], [132, 0, 0x04, 0, 7, 0, 42, 0]);
+        assert_eq!(set[54..96], utf16le("DeviceInterfaceGUIDs\0")[..]);
+        assert_eq!(set[96..98], [80, 0]);
+        assert_eq!(set[98..178], utf16le("{8b5a94c3-7f2e-4f6b-9d1a-3c6e0b2f7a51}\0\0")[..]);
+    }
+
+    #[test]
+    fn descriptor_set_first_interface() {
+        for interface in [0u8, 2, 5].iter() {
+            let set = descriptor_set(*interface);
+            assert_eq!(set[22], *interface);
+            // only bFirstInterface depends on the interface
+            let mut expected = descriptor_set(3);
+            expected[22] = *interface;
+            assert_eq!(set, expected);
+        }
+    }
+}
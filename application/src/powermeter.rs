@@ -1,6 +1,39 @@
 use crate::filter::{self, Filter};
 use core::fmt::Write;
 
+// the current sense amplifier saturates at ~6.2A, report anything above this as overcurrent
+pub const OVERCURRENT_LIMIT_A: f32 = 5.0;
+
+// convert the current sense ADC reading to amps
+pub fn current_from_raw(raw: u16) -> f32 {
+        let current_v = (raw as f32 - 2048.0) * 3.3 / 4096.0;
+        -current_v / 0.264
+}
+
+// convert the vout sense ADC reading to the input voltage in volts
+pub fn voltage_from_raw(raw: u16) -> f32 {
+        // we get vout from the voltage divider, in 12 bits, 3.3V is 4096
+        let vout_sense_v = (raw as f32) * 3.3 / 4096.0;
+        // we do the reverse calculation to figure out the input voltage
+        let r8 = 2400.0; // R8 is the top resistor in the voltage divider
+        let r9 = 470.0; // R9 is the bottom resistor in the voltage divider
+        vout_sense_v * (r8 + r9) / r9
+}
+
+// averaged readings, published by the ADC task for readers that can't lock the power meter
+#[derive(Clone, Copy)]
+pub struct PowerReadings {
+        pub voltage: f32,
+        pub current: f32,
+        pub power: f32,
+}
+
+impl PowerReadings {
+        pub fn new() -> Self {
+                Self{voltage: 0.0, current: 0.0, power: 0.0}
+        }
+}
+
 pub trait PowerMeter {
         fn get_power(&mut self) -> f32;
         fn get_voltage(&mut self) -> f32;
@@ -10,6 +43,11 @@ pub trait PowerMeter {
         fn write_trace(&mut self, writer: &mut dyn Write);
         fn write(&mut self, writer: &mut dyn Write);
 
+        fn readings(&mut self) -> PowerReadings {
+                PowerReadings{voltage: self.get_voltage(),
+                              current: self.get_current(),
+                              power: self.get_power()}
+        }
 }
 
 // Moving average power meter
@@ -0,0 +1,281 @@
+use core::mem::{offset_of, size_of};
+
+use stm32f4xx_hal::flash::LockedFlash;
+
+use crate::config::{as_mut_u8_slice, as_u8_slice, erase_sector, program_flash, value_bytes, ConfigBlock, ConfigError};
+use crate::ctlpins::PinState;
+
+// DUT profiles are stored in the 2nd sector of the flash memory, starting at 0x0800_8000,
+// right after the bootloader. Like the config area, the 16k sector is used as a log of 1k
+// ProfileBlocks: saving or deleting a profile appends a block, and the last valid block for
+// a profile id is the one in use. When all blocks are used, the latest block of every
+// profile is copied to a static scratch buffer, the sector is erased and the blocks are
+// written back. The buffer is static as compacting runs from the USB interrupt, where 5k
+// of stack is too much. There is no other sector to stage the blocks in, so a power loss
+// between the erase and the end of the rewrite loses the profiles and the boot script. The
+// window is a few ms once every PROFILE_SLOTS writes, the host then has to store them again.
+//
+// Every block carries the erase count of the sector, like the config blocks do. After a
+// plain erase a deleted boot script block is written to keep it.
+//
+// The boot script is kept in the same log, as a block with the reserved AUTOEXEC_ID holding
+// the script in its json field, so loading a profile doesn't change it.
+
+const FLASH_SECTOR : u8 = 2;
+const FLASH_PROFILE_BASE : usize = 0x0800_8000; // see memory.x
+const PROFILE_SLOTS : usize = 16;
+const MAGIC : u32 = 0x9f0f11e5;
+const FLAG_DELETED : u8 = 0x01;
+
+const AUTOEXEC_ID : u8 = 0x80;
+
+pub const MAX_PROFILES : usize = 4;
+pub const PROFILE_NAME_LENGTH : usize = 16;
+pub const AUTOEXEC_LENGTH : usize = 512;
+
+// the latest block of every profile and of the boot script
+const SCRATCH_LENGTH : usize = (MAX_PROFILES + 1) * size_of::<ProfileBlock>();
+
+// a copy of all the DUT related settings of a ConfigBlock, plus the default pin states
+#[repr(C, packed)]
+pub struct ProfileBlock {
+    id: u8,
+    flags: u8,
+    pub name: [u8; PROFILE_NAME_LENGTH], // profile name
+    pub dut_name: [u8; 64],
+    pub tags: [u8; 256],
+    pub usb_console: [u8; 64],
+    pub power_on: [u8; 32],
+    pub power_off: [u8; 32],
+    pub power_rescue: [u8; 32],
+    pub json: [u8; 512], // or the boot script, in the AUTOEXEC_ID block
+    pins: [u8; 5], // default state of reset, a, b, c and d as in the power sequences: h, l or z
+    erase_count: u32, // times the sector has been erased, carried over on every write
+    padding: [u8; 1024-1-1-PROFILE_NAME_LENGTH-64-256-64-32-32-32-512-5-4-4],
+    magic: u32,
+}
+
+impl ProfileBlock {
+    fn new() -> Self {
+        ProfileBlock {
+            id: 0,
+            flags: 0,
+            name: [0; PROFILE_NAME_LENGTH],
+            dut_name: [0; 64],
+            tags: [0; 256],
+            usb_console: [0; 64],
+            power_on: [0; 32],
+            power_off: [0; 32],
+            power_rescue: [0; 32],
+            json: [0; 512],
+            pins: [0; 5],
+            erase_count: 0,
+            padding: [0; 1024-1-1-PROFILE_NAME_LENGTH-64-256-64-32-32-32-512-5-4-4],
+            magic: MAGIC,
+        }
+    }
+
+    fn from_config(id: u8, name: &[u8], cfg: &ConfigBlock, pins: &[PinState; 5]) -> Result<Self, ConfigError> {
+        let mut profile = ProfileBlock::new();
+        if name.len() > profile.name.len() {
+            return Err(ConfigError::TooLong);
+        }
+        profile.id = id;
+        profile.name[..name.len()].copy_from_slice(name);
+        profile.dut_name = cfg.name;
+        profile.tags = cfg.tags;
+        profile.usb_console = cfg.usb_console;
+        profile.power_on = cfg.power_on;
+        profile.power_off = cfg.power_off;
+        profile.power_rescue = cfg.power_rescue;
+        profile.json = cfg.json;
+        for (p, state) in profile.pins.iter_mut().zip(pins.iter()) {
+            *p = state.as_u8();
+        }
+        Ok(profile)
+    }
+
+    // copy the profile settings into a config block
+    pub fn to_config(&self, mut cfg: ConfigBlock) -> ConfigBlock {
+        cfg.name = self.dut_name;
+        cfg.tags = self.tags;
+        cfg.usb_console = self.usb_console;
+        cfg.power_on = self.power_on;
+        cfg.power_off = self.power_off;
+        cfg.power_rescue = self.power_rescue;
+        cfg.json = self.json;
+        cfg
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn pins(&self) -> [PinState; 5] {
+        let mut pins = [PinState::Floating; 5];
+        for (state, p) in pins.iter_mut().zip(self.pins.iter()) {
+            *state = PinState::from_u8(*p).unwrap_or(PinState::Floating);
+        }
+        pins
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+    }
+
+    fn format_error(&self) -> bool {
+        self.magic != MAGIC && self.magic != 0xFFFF_FFFF
+    }
+
+    fn is_deleted(&self) -> bool {
+        self.flags & FLAG_DELETED != 0
+    }
+
+    fn copy(&self) -> Self {
+        let mut profile = ProfileBlock::new();
+        unsafe { as_mut_u8_slice(&mut profile) }.copy_from_slice(unsafe { as_u8_slice(self) });
+        profile
+    }
+}
+
+// The flash area in 0x0800_8000 - 0x0800_BFFF is reserved for the profile blocks.
+#[repr(C, packed)]
+struct ProfileAreaFlash {
+    profiles: [ProfileBlock; PROFILE_SLOTS],
+    // DO NOT ADD MORE VARIABLES HERE
+}
+
+pub struct ProfileArea {
+    flash_profiles: &'static ProfileAreaFlash,
+    scratch: &'static mut [u8; SCRATCH_LENGTH], // blocks kept while compacting
+    erase_count: u32,
+}
+
+impl ProfileArea {
+    pub fn new(flash: &mut LockedFlash) -> Self {
+        let flash_profiles = unsafe { &*(FLASH_PROFILE_BASE as *const ProfileAreaFlash) };
+        let mut area = ProfileArea {
+            flash_profiles,
+            // there is a single ProfileArea, created from init
+            scratch: cortex_m::singleton!(: [u8; SCRATCH_LENGTH] = [0; SCRATCH_LENGTH]).unwrap(),
+            erase_count: flash_profiles.profiles.iter().filter(|p| p.is_valid()).map(|p| p.erase_count).max().unwrap_or(0),
+        };
+        if area.flash_profiles.profiles.iter().any(|p| p.format_error()) {
+            // nobody to report to at boot, a failing sector will show up on the next write
+            area.erase(flash).ok();
+        }
+        area
+    }
+
+    pub fn erase(&mut self, flash: &mut LockedFlash) -> Result<(), ConfigError> {
+        self.erase_sector(flash)?;
+        let mut block = ProfileBlock::new();
+        block.id = AUTOEXEC_ID;
+        block.flags |= FLAG_DELETED;
+        block.erase_count = self.erase_count;
+        program_flash(flash, FLASH_PROFILE_BASE, unsafe { as_u8_slice(&block) })
+    }
+
+    fn erase_sector(&mut self, flash: &mut LockedFlash) -> Result<(), ConfigError> {
+        // a failed erase may have worn the sector as well
+        self.erase_count = self.erase_count.saturating_add(1);
+        erase_sector(flash, FLASH_SECTOR)
+    }
+
+    // times the sector has been erased
+    pub fn erase_count(&self) -> u32 {
+        self.erase_count
+    }
+
+    // latest block of a profile, None if it was never saved or has been deleted
+    pub fn get(&self, id: u8) -> Option<&ProfileBlock> {
+        latest(self.flash_profiles, id)
+    }
+
+    pub fn find(&self, name: &[u8]) -> Option<&ProfileBlock> {
+        self.iter().find(|p| value_bytes(&p.name) == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ProfileBlock> {
+        (0..MAX_PROFILES as u8).filter_map(move |id| self.get(id))
+    }
+
+    // save the DUT settings of cfg, replacing the profile with the same name if any
+    pub fn save(&mut self, flash: &mut LockedFlash, name: &[u8], cfg: &ConfigBlock, pins: &[PinState; 5]) -> Result<u8, ConfigError> {
+        let id = match self.find(name) {
+            Some(p) => p.id,
+            None => (0..MAX_PROFILES as u8).find(|id| self.get(*id).is_none()).ok_or(ConfigError::NoSpace)?,
+        };
+        let mut profile = ProfileBlock::from_config(id, name, cfg, pins)?;
+        self.append(flash, &mut profile)?;
+        Ok(id)
+    }
+
+    // returns the id of the deleted profile
+    pub fn delete(&mut self, flash: &mut LockedFlash, name: &[u8]) -> Result<u8, ConfigError> {
+        let mut profile = self.find(name).ok_or(ConfigError::NotFound)?.copy();
+        profile.flags |= FLAG_DELETED;
+        self.append(flash, &mut profile)?;
+        Ok(profile.id)
+    }
+
+    // the boot script, empty when none is stored
+    pub fn autoexec(&self) -> &[u8] {
+        self.get(AUTOEXEC_ID).map_or(&[], |p| value_bytes(&p.json))
+    }
+
+    // store the boot script, an empty one deletes it
+    pub fn set_autoexec(&mut self, flash: &mut LockedFlash, script: &[u8]) -> Result<(), ConfigError> {
+        let mut block = ProfileBlock::new();
+        if script.len() > AUTOEXEC_LENGTH {
+            return Err(ConfigError::TooLong);
+        }
+        block.id = AUTOEXEC_ID;
+        block.json[..script.len()].copy_from_slice(script);
+        if script.is_empty() {
+            block.flags |= FLAG_DELETED;
+        }
+        self.append(flash, &mut block)
+    }
+
+    fn append(&mut self, flash: &mut LockedFlash, profile: &mut ProfileBlock) -> Result<(), ConfigError> {
+        let next = match self.flash_profiles.profiles.iter().position(|p| !p.is_valid()) {
+            Some(i) => i,
+            None => self.compact(flash, profile.id)?,
+        };
+        profile.erase_count = self.erase_count;
+        let buffer = unsafe { as_u8_slice(profile) };
+        program_flash(flash, FLASH_PROFILE_BASE + next * size_of::<ProfileBlock>(), buffer)
+    }
+
+    // erase the sector keeping the latest block of every profile and of the boot script
+    // except skip_id, returns the first free slot
+    fn compact(&mut self, flash: &mut LockedFlash, skip_id: u8) -> Result<usize, ConfigError> {
+        let area = self.flash_profiles;
+        let ids = (0..MAX_PROFILES as u8).chain(core::iter::once(AUTOEXEC_ID));
+        let block_size = size_of::<ProfileBlock>();
+        let mut count = 0;
+        for p in ids.filter_map(|id| latest(area, id)).filter(|p| p.id != skip_id) {
+            self.scratch[count * block_size..][..block_size].copy_from_slice(unsafe { as_u8_slice(p) });
+            count += 1;
+        }
+
+        self.erase_sector(flash)?;
+        let erase_count = self.erase_count.to_ne_bytes();
+        for (i, block) in self.scratch[..count * block_size].chunks_mut(block_size).enumerate() {
+            block[offset_of!(ProfileBlock, erase_count)..][..erase_count.len()].copy_from_slice(&erase_count);
+            program_flash(flash, FLASH_PROFILE_BASE + i * block_size, block)?;
+        }
+        Ok(count)
+    }
+}
+
+// the blocks are in flash, so they outlive the ProfileArea borrowed while compacting
+fn latest(area: &'static ProfileAreaFlash, id: u8) -> Option<&'static ProfileBlock> {
+    for p in area.profiles.iter().rev() {
+        if p.is_valid() && p.id == id {
+            return if p.is_deleted() { None } else { Some(p) };
+        }
+    }
+    None
+}
@@ -0,0 +1,316 @@
+use core::fmt::{self, Write};
+
+use arrayvec::ArrayString;
+
+use crate::config::value_bytes;
+
+// Reply of a shell command. Commands write their human readable message into the reply, and
+// in json mode the reply is sent as one JSON object per line, so automation doesn't need to
+// scrape the messages:
+//
+//   {"cmd":"meter","ok":true,"code":0,"message":"0.12A 12.01V 1.44W","data":{"current_a":0.12,...}}
+//
+// "code" is one of the CODE_* values below, "ok" is true when it is CODE_OK, and "data" holds
+// the command specific fields, documented next to each command in shell.rs. Fields may be
+// added but are never renamed or removed. A reply too long for its buffers drops the fields
+// that don't fit whole, and the end of its message, and gets "truncated":true.
+
+pub const CODE_OK: u8 = 0;
+pub const CODE_USAGE: u8 = 1;       // invalid arguments, the message holds the usage
+pub const CODE_FAILED: u8 = 2;      // the command ran but failed, the message tells why
+pub const CODE_UNSUPPORTED: u8 = 3; // unknown command
+
+#[derive(Clone, Copy)]
+pub enum Value<'a> {
+    Str(&'a str),
+    Bytes(&'a [u8]), // config values, NUL padded and not always valid UTF-8
+    Int(i64),
+    Float(f32),
+    Bool(bool),
+    Null,
+}
+
+const MESSAGE_LENGTH: usize = 1024;
+const DATA_LENGTH: usize = 2048;
+
+pub struct Reply {
+    message: ArrayString<MESSAGE_LENGTH>,
+    data: ArrayString<DATA_LENGTH>, // members of the data object, without the braces
+    code: u8,
+    depth: usize,     // objects and arrays open in data, room is kept to close them
+    skipped: usize,   // objects and arrays that didn't fit, their content is dropped
+    truncated: bool,
+}
+
+impl Reply {
+    pub fn new() -> Self {
+        Reply {
+            message: ArrayString::new(),
+            data: ArrayString::new(),
+            code: CODE_OK,
+            depth: 0,
+            skipped: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.message.is_empty()
+    }
+
+    // mark the command as failed, the message written next tells why
+    pub fn fail(&mut self, code: u8) -> &mut Self {
+        self.code = code;
+        self
+    }
+
+    pub fn usage(&mut self, usage: &str) {
+        self.code = CODE_USAGE;
+        write!(self, "usage: {}", usage).ok();
+    }
+
+    pub fn field(&mut self, key: &str, value: Value) {
+        self.append(0, |data| {
+            write_key(data, Some(key))?;
+            write_value(data, value)
+        });
+    }
+
+    // element of an array opened with open(.., '[')
+    pub fn element(&mut self, value: Value) {
+        self.append(0, |data| {
+            write_key(data, None)?;
+            write_value(data, value)
+        });
+    }
+
+    // start a nested object or array, key is None for array elements
+    pub fn open(&mut self, key: Option<&str>, bracket: char) {
+        let opened = self.append(1, |data| {
+            write_key(data, key)?;
+            data.write_char(bracket)
+        });
+        if opened {
+            self.depth += 1;
+        } else {
+            self.skipped += 1;
+        }
+    }
+
+    pub fn close(&mut self, bracket: char) {
+        if self.skipped > 0 {
+            self.skipped -= 1;
+        } else if self.depth > 0 {
+            // the room was kept by open
+            self.depth -= 1;
+            self.data.push(bracket);
+        }
+    }
+
+    // write to data, undone when it doesn't fit whole with the brackets closing the open
+    // objects and arrays plus the opened ones, so data stays valid JSON
+    fn append(&mut self, opened: usize, write: impl FnOnce(&mut ArrayString<DATA_LENGTH>) -> fmt::Result) -> bool {
+        if self.skipped > 0 {
+            return false;
+        }
+        let length = self.data.len();
+        if write(&mut self.data).is_err() || self.data.len() + self.depth + opened > DATA_LENGTH {
+            self.data.truncate(length);
+            self.truncated = true;
+            return false;
+        }
+        true
+    }
+
+    pub fn write_text(&self, out: &mut dyn Write) {
+        out.write_str(&self.message).ok();
+    }
+
+    pub fn write_json(&self, cmd: &str, out: &mut dyn Write) {
+        out.write_str("{\"cmd\":").ok();
+        write_json_str(out, cmd).ok();
+        write!(out, ",\"ok\":{},\"code\":{},\"message\":", self.code == CODE_OK, self.code).ok();
+        // messages span several lines in text mode, a JSON string can't
+        write_json_str(out, &self.message).ok();
+        write!(out, ",\"data\":{{{}}}", self.data).ok();
+        if self.truncated {
+            out.write_str(",\"truncated\":true").ok();
+        }
+        out.write_char('}').ok();
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.message.try_push_str(s).map_err(|_| {
+            self.truncated = true;
+            fmt::Error
+        })
+    }
+}
+
+fn write_key(data: &mut ArrayString<DATA_LENGTH>, key: Option<&str>) -> fmt::Result {
+    if !data.is_empty() && !data.ends_with(&['{', '[', ':'][..]) {
+        data.write_char(',')?;
+    }
+    if let Some(key) = key {
+        write_json_str(data, key)?;
+        data.write_char(':')?;
+    }
+    Ok(())
+}
+
+fn write_value(data: &mut ArrayString<DATA_LENGTH>, value: Value) -> fmt::Result {
+    match value {
+        Value::Str(s) => write_json_str(data, s),
+        Value::Bytes(b) => write_json_str(data, utf8_prefix(value_bytes(b))),
+        Value::Int(i) => write!(data, "{}", i),
+        Value::Float(f) if f.is_finite() => write!(data, "{:.3}", f),
+        Value::Float(_) | Value::Null => data.write_str("null"),
+        Value::Bool(b) => write!(data, "{}", b),
+    }
+}
+
+// the valid UTF-8 part of a config value, a JSON string can't hold anything else
+fn utf8_prefix(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+// quoted and escaped JSON string
+fn write_json_str<W: Write + ?Sized>(out: &mut W, s: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            '\r' => out.write_str("\\r")?,
+            '\n' => out.write_str("\\n")?,
+            '\t' => out.write_str("\\t")?,
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.write_char(c)?,
+        }
+    }
+    out.write_char('"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(reply: &Reply) -> String {
+        let mut out = String::new();
+        reply.write_json("test", &mut out);
+        out
+    }
+
+    #[test]
+    fn text_and_json() {
+        let mut reply = Reply::new();
+        write!(reply, "Device powered on").ok();
+        reply.field("on", Value::Bool(true));
+        reply.field("power_w", Value::Float(1.5));
+        reply.field("time_ms", Value::Null);
+        let mut text = String::new();
+        reply.write_text(&mut text);
+        assert_eq!(text, "Device powered on");
+        assert_eq!(json(&reply), r#"{"cmd":"test","ok":true,"code":0,"message":"Device powered on","data":{"on":true,"power_w":1.500,"time_ms":null}}"#);
+    }
+
+    #[test]
+    fn failures() {
+        let mut reply = Reply::new();
+        reply.usage("meter on|read|off");
+        assert_eq!(json(&reply), r#"{"cmd":"test","ok":false,"code":1,"message":"usage: meter on|read|off","data":{}}"#);
+        let mut reply = Reply::new();
+        write!(reply.fail(CODE_FAILED), "Failed").ok();
+        assert!(json(&reply).starts_with(r#"{"cmd":"test","ok":false,"code":2,"#));
+    }
+
+    #[test]
+    fn escapes() {
+        let mut reply = Reply::new();
+        write!(reply, "a \"quoted\" C:\\path\r\nnext\tline").ok();
+        reply.field("k\"ey", Value::Str("\u{1}\u{1f}\u{7f}é"));
+        reply.field("bytes", Value::Bytes(b"a\\b\0padding"));
+        reply.field("invalid", Value::Bytes(b"ok\xffno"));
+        reply.field("nan", Value::Float(f32::NAN));
+        assert_eq!(
+            json(&reply),
+            r#"{"cmd":"test","ok":true,"code":0,"message":"a \"quoted\" C:\\path\r\nnext\tline","data":{"k\"ey":"\u0001\u001f\u007fé","bytes":"a\\b","invalid":"ok","nan":null}}"#
+        );
+    }
+
+    #[test]
+    fn nested() {
+        let mut reply = Reply::new();
+        reply.open(Some("pins"), '[');
+        reply.element(Value::Str("h"));
+        reply.open(None, '{');
+        reply.field("a", Value::Int(-1));
+        reply.close('}');
+        reply.close(']');
+        reply.field("n", Value::Int(2));
+        assert!(json(&reply).ends_with(r#""data":{"pins":["h",{"a":-1}],"n":2}}"#));
+    }
+
+    #[test]
+    fn data_overflow_stays_valid() {
+        let mut reply = Reply::new();
+        let long = "x".repeat(600);
+        reply.open(Some("list"), '[');
+        for _ in 0..4 {
+            reply.element(Value::Str(&long));
+        }
+        reply.open(None, '{');
+        reply.field("inner", Value::Str(&long));
+        reply.close('}');
+        reply.close(']');
+        reply.field("after", Value::Int(1));
+        let out = json(&reply);
+        // three strings fit, the fourth and the field of the nested object are dropped whole
+        assert_eq!(out.matches(&long).count(), 3);
+        assert!(!out.contains("inner"));
+        assert!(out.ends_with(r#"",{}],"after":1},"truncated":true}"#), "{}", out);
+    }
+
+    #[test]
+    fn data_fills_up_to_the_closing_brackets() {
+        let mut reply = Reply::new();
+        reply.open(Some("a"), '[');
+        // "a":[ takes 5 bytes, and the closing bracket needs one
+        let fits = "x".repeat(DATA_LENGTH - 5 - 1 - 2);
+        reply.element(Value::Str(&fits));
+        reply.element(Value::Int(1));
+        reply.close(']');
+        let out = json(&reply);
+        assert!(out.contains(&format!(r#""data":{{"a":["{}"]}},"truncated":true}}"#, fits)), "{}", out);
+    }
+
+    #[test]
+    fn open_that_doesnt_fit_drops_its_content() {
+        let mut reply = Reply::new();
+        // "f":"..." leaves 8 bytes, too few for ,"list":[]
+        reply.field("f", Value::Str(&"x".repeat(DATA_LENGTH - 6 - 8)));
+        reply.open(Some("list"), '[');
+        reply.element(Value::Int(1));
+        reply.open(None, '{');
+        reply.close('}');
+        reply.close(']');
+        reply.field("n", Value::Int(2));
+        assert!(json(&reply).ends_with(r#"","n":2},"truncated":true}"#));
+    }
+
+    #[test]
+    fn message_overflow() {
+        let mut reply = Reply::new();
+        write!(reply, "{}", "m".repeat(MESSAGE_LENGTH)).ok();
+        assert!(!json(&reply).contains("truncated"));
+        write!(reply, "more").ok();
+        let out = json(&reply);
+        assert!(out.ends_with(r#""data":{},"truncated":true}"#));
+        assert!(!out.contains("more"));
+    }
+}
@@ -0,0 +1,343 @@
+use core::fmt::{self, Write};
+
+use arrayvec::ArrayString;
+
+use crate::powermeter::PowerReadings;
+
+// Shell command batches, so small lab workflows run on the device without a round trip per
+// command:
+//
+//   storage host; sleep 2000; storage dut; power on; sleep 500; expect current > 0.05
+//
+// Commands are separated by ';', unless quoted or escaped with a backslash. The batch runs
+// from the USB task: sleep only sets the time to resume at, and the periodic task pends the
+// USB interrupt once it has passed, so the device keeps serving USB requests meanwhile.
+// A failed expect drops the rest of the batch, like entering a new command line does.
+//
+// The boot script is a batch stored with the config, started from init after BOOT_DELAY_MS.
+// Its output goes to the BootLog instead of the shell, as nobody may be connected, and
+// pressing the button stops it, so a press right after power up skips it entirely.
+
+pub const BATCH_LENGTH: usize = 512;
+pub const MAX_SLEEP_MS: u32 = 600_000;
+pub const BOOT_DELAY_MS: u32 = 2_000;
+pub const BOOT_LOG_LENGTH: usize = 1024;
+
+pub struct Batch {
+    commands: ArrayString<BATCH_LENGTH>, // not run yet, still ';' separated
+    running: bool,
+    captured: bool, // the boot script, writing to the BootLog
+    resume_ms: u64, // uptime at which a sleeping batch continues
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch { commands: ArrayString::new(), running: false, captured: false, resume_ms: 0 }
+    }
+
+    // replaces any running batch, false when the line doesn't fit
+    pub fn start(&mut self, cmd: &str, args: &str) -> bool {
+        self.commands.clear();
+        self.captured = false;
+        self.resume_ms = 0;
+        self.running = self.commands.try_push_str(cmd).is_ok()
+            && (args.is_empty() || (self.commands.try_push(' ').is_ok() && self.commands.try_push_str(args).is_ok()));
+        if !self.running {
+            self.commands.clear();
+        }
+        self.running
+    }
+
+    // start the boot script once the uptime reaches BOOT_DELAY_MS
+    pub fn boot(&mut self, script: &str) -> bool {
+        self.commands.clear();
+        self.captured = true;
+        self.resume_ms = BOOT_DELAY_MS as u64;
+        self.running = self.commands.try_push_str(script).is_ok();
+        self.running
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn is_captured(&self) -> bool {
+        self.captured
+    }
+
+    // true when the next command can run
+    pub fn is_due(&self, now_ms: u64) -> bool {
+        self.running && now_ms >= self.resume_ms
+    }
+
+    pub fn sleep(&mut self, ms: u32, now_ms: u64) {
+        self.resume_ms = now_ms + ms.min(MAX_SLEEP_MS) as u64;
+    }
+
+    // drop the commands left, the batch ends at the next call to next
+    pub fn abort(&mut self) {
+        self.commands.clear();
+        self.resume_ms = 0;
+    }
+
+    // the next command with its escapes for ';' removed, None at the end of the batch,
+    // which also ends it
+    pub fn next(&mut self) -> Option<ArrayString<BATCH_LENGTH>> {
+        loop {
+            if self.commands.is_empty() {
+                self.running = false;
+                return None;
+            }
+            let mut command = ArrayString::<BATCH_LENGTH>::new();
+            let mut quoted = false;
+            let mut escaped = false;
+            let mut end = self.commands.len();
+            for (i, c) in self.commands.char_indices() {
+                if escaped {
+                    escaped = false;
+                    if c != ';' {
+                        command.push('\\');
+                    }
+                    command.push(c);
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == ';' && !quoted {
+                    end = i;
+                    break;
+                } else {
+                    if c == '"' {
+                        quoted = !quoted;
+                    }
+                    command.push(c);
+                }
+            }
+            if escaped {
+                command.push('\\');
+            }
+
+            let rest = ArrayString::<BATCH_LENGTH>::from(self.commands.get(end + 1..).unwrap_or("")).unwrap_or_default();
+            self.commands = rest;
+            // empty commands between separators are skipped
+            if !command.trim().is_empty() {
+                return Some(command);
+            }
+        }
+    }
+}
+
+// output of the last boot script, the end is dropped once full
+pub struct BootLog {
+    text: ArrayString<BOOT_LOG_LENGTH>,
+    truncated: bool,
+}
+
+impl BootLog {
+    pub fn new() -> Self {
+        BootLog { text: ArrayString::new(), truncated: false }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl Write for BootLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.text.try_push(c).is_err() {
+                self.truncated = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Quantity {
+    Current,
+    Voltage,
+    Power,
+}
+
+#[derive(Clone, Copy)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// condition of if and expect:
+//   current|voltage|power <|<=|>|>= value  reading of the power meter, in A, V and W
+//   pin r|a|b|c|d low|high                 sensed level of a CTL pin
+#[derive(Clone, Copy)]
+pub enum Condition {
+    Meter(Quantity, Comparison, f32),
+    Pin(usize, bool), // index in the sense_pins order, expected level
+}
+
+pub const CONDITION_USAGE: &str = "current|voltage|power <|<=|>|>= value, or pin r|a|b|c|d low|high";
+
+impl Condition {
+    // parses the condition at the start of args, returns it with the rest of args
+    pub fn parse(args: &str) -> Option<(Condition, &str)> {
+        let mut words = args.trim_start().splitn(4, char::is_whitespace);
+        let (subject, op, value) = (words.next()?, words.next()?, words.next()?);
+        let rest = words.next().unwrap_or("").trim_start();
+
+        let condition = if subject == "pin" {
+            let pin = ["r", "a", "b", "c", "d"].iter().position(|p| *p == op)?;
+            match value {
+                "low" => Condition::Pin(pin, false),
+                "high" => Condition::Pin(pin, true),
+                _ => return None,
+            }
+        } else {
+            let quantity = match subject {
+                "current" => Quantity::Current,
+                "voltage" => Quantity::Voltage,
+                "power" => Quantity::Power,
+                _ => return None,
+            };
+            let comparison = match op {
+                "<" => Comparison::Less,
+                "<=" => Comparison::LessOrEqual,
+                ">" => Comparison::Greater,
+                ">=" => Comparison::GreaterOrEqual,
+                _ => return None,
+            };
+            Condition::Meter(quantity, comparison, value.parse().ok()?)
+        };
+        Some((condition, rest))
+    }
+
+    pub fn holds(&self, readings: &PowerReadings, pins: &[bool; 5]) -> bool {
+        match *self {
+            Condition::Meter(quantity, comparison, limit) => {
+                let value = measure(quantity, readings);
+                match comparison {
+                    Comparison::Less => value < limit,
+                    Comparison::LessOrEqual => value <= limit,
+                    Comparison::Greater => value > limit,
+                    Comparison::GreaterOrEqual => value >= limit,
+                }
+            },
+            Condition::Pin(pin, high) => pins[pin] == high,
+        }
+    }
+}
+
+pub fn measure(quantity: Quantity, readings: &PowerReadings) -> f32 {
+    match quantity {
+        Quantity::Current => readings.current,
+        Quantity::Voltage => readings.voltage,
+        Quantity::Power => readings.power,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(line: &str) -> Vec<String> {
+        let mut batch = Batch::new();
+        let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+        assert!(batch.start(cmd, args));
+        let commands = core::iter::from_fn(|| batch.next()).map(|c| c.to_string()).collect();
+        assert!(!batch.is_running());
+        commands
+    }
+
+    fn readings(voltage: f32, current: f32) -> PowerReadings {
+        PowerReadings { voltage, current, power: voltage * current }
+    }
+
+    #[test]
+    fn batch_commands() {
+        assert_eq!(commands("status"), ["status"]);
+        assert_eq!(commands("storage host; sleep 2000; power on"), ["storage host", " sleep 2000", " power on"]);
+        assert_eq!(commands("power on;; ;status;"), ["power on", "status"]);
+    }
+
+    #[test]
+    fn batch_quotes_and_escapes() {
+        assert_eq!(commands(r#"send "a;b"; status"#), [r#"send "a;b""#, " status"]);
+        assert_eq!(commands(r"send a\;b; status"), ["send a;b", " status"]);
+        // other escapes are left for the command, a quote escaped doesn't start a quoted part
+        assert_eq!(commands(r#"send a\"b; send c\r\"#), [r#"send a\"b"#, r" send c\r\"]);
+    }
+
+    #[test]
+    fn batch_too_long() {
+        let mut batch = Batch::new();
+        assert!(!batch.start("send", &"x".repeat(BATCH_LENGTH)));
+        assert!(!batch.is_running());
+        assert!(batch.next().is_none());
+        assert!(batch.start("send", &"x".repeat(BATCH_LENGTH - 5)));
+    }
+
+    #[test]
+    fn batch_sleep_and_abort() {
+        let mut batch = Batch::new();
+        assert!(batch.start("sleep", "100; status"));
+        assert_eq!(batch.next().unwrap().as_str(), "sleep 100");
+        batch.sleep(100, 1000);
+        assert!(!batch.is_due(1099));
+        assert!(batch.is_due(1100));
+        batch.sleep(u32::MAX, 0);
+        assert!(!batch.is_due(MAX_SLEEP_MS as u64 - 1));
+        batch.abort();
+        assert!(batch.is_due(0));
+        assert!(batch.next().is_none());
+        assert!(!batch.is_running());
+    }
+
+    #[test]
+    fn boot_script() {
+        let mut batch = Batch::new();
+        assert!(batch.boot("power on"));
+        assert!(batch.is_captured());
+        assert!(!batch.is_due(BOOT_DELAY_MS as u64 - 1));
+        assert!(batch.is_due(BOOT_DELAY_MS as u64));
+        assert!(batch.start("status", ""));
+        assert!(!batch.is_captured());
+    }
+
+    #[test]
+    fn meter_conditions() {
+        let (condition, rest) = Condition::parse(" current > 0.05 power off").unwrap();
+        assert_eq!(rest, "power off");
+        assert!(condition.holds(&readings(5.0, 0.1), &[false; 5]));
+        assert!(!condition.holds(&readings(5.0, 0.05), &[false; 5]));
+
+        let holds = |args: &str, readings: &PowerReadings| Condition::parse(args).unwrap().0.holds(readings, &[false; 5]);
+        assert!(holds("voltage <= 5", &readings(5.0, 0.0)));
+        assert!(!holds("voltage < 5", &readings(5.0, 0.0)));
+        assert!(holds("power >= 1.5", &readings(5.0, 0.3)));
+        assert!(holds("current < -0.5e-1", &readings(5.0, -0.1)));
+        assert_eq!(Condition::parse("voltage > 1").unwrap().1, "");
+    }
+
+    #[test]
+    fn pin_conditions() {
+        let pins = [false, true, false, false, true];
+        let holds = |args: &str| Condition::parse(args).unwrap().0.holds(&readings(0.0, 0.0), &pins);
+        assert!(holds("pin a high"));
+        assert!(holds("pin r low"));
+        assert!(holds("pin d high"));
+        assert!(!holds("pin b high"));
+    }
+
+    #[test]
+    fn invalid_conditions() {
+        for args in ["", "current", "current >", "current = 1", "current > x", "temperature > 1",
+                     "pin e low", "pin a 1", "pin A low"].iter() {
+            assert!(Condition::parse(args).is_none(), "{}", args);
+        }
+    }
+}
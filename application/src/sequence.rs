@@ -0,0 +1,234 @@
+use crate::ctlpins::{CTLPinsTrait, PinState};
+
+// Runs pin sequences sent by the host without blocking, one step per tick. The format is
+// the one of the power sequences, see ctlpins.rs, but validated up front: unknown orders
+// or states are rejected instead of ignored. Pin orders go through the CTLPinsTrait
+// setters, so the commanded state is tracked and high outputs wait for the DUT power.
+
+pub const MAX_SEQUENCE_LENGTH: usize = 256;
+const WAIT_UNIT_MS: u32 = 100;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SequenceState {
+    Idle,
+    Running,
+    Done,
+    Aborted,
+}
+
+#[derive(Clone, Copy)]
+enum Order {
+    Pin(u8, PinState), // a, b, c, d or r
+    Wait(u32),         // in WAIT_UNIT_MS
+    Power(bool),
+}
+
+// parse the order starting at p, returns the order and the offset right after it,
+// None at the end of the sequence, or the offset of the offending byte
+fn parse_order(sequence: &[u8], mut p: usize) -> Result<Option<(Order, usize)>, usize> {
+    while sequence.get(p) == Some(&b',') {
+        p += 1;
+    }
+    let order = match sequence.get(p) {
+        None | Some(b'\0') => return Ok(None),
+        Some(c) => c.to_ascii_lowercase(),
+    };
+    match order {
+        b'a' | b'b' | b'c' | b'd' | b'r' => {
+            let state = sequence.get(p + 1).and_then(|c| PinState::from_u8(*c)).ok_or(p + 1)?;
+            Ok(Some((Order::Pin(order, state), p + 2)))
+        }
+        b'w' => {
+            let mut end = p + 1;
+            let mut wait: u32 = 0;
+            while let Some(c) = sequence.get(end).filter(|c| c.is_ascii_digit()) {
+                wait = wait.saturating_mul(10).saturating_add((c - b'0') as u32);
+                end += 1;
+            }
+            if end == p + 1 {
+                return Err(end);
+            }
+            Ok(Some((Order::Wait(wait), end)))
+        }
+        b'p' => match sequence.get(p + 1) {
+            Some(b'0') => Ok(Some((Order::Power(false), p + 2))),
+            Some(b'1') => Ok(Some((Order::Power(true), p + 2))),
+            _ => Err(p + 1),
+        },
+        _ => Err(p),
+    }
+}
+
+// check the whole sequence, returns the offset of the first invalid byte
+pub fn validate(sequence: &[u8]) -> Result<(), usize> {
+    let mut p = 0;
+    while let Some((_, next)) = parse_order(sequence, p)? {
+        p = next;
+    }
+    Ok(())
+}
+
+pub struct Sequencer {
+    sequence: heapless::Vec<u8, MAX_SEQUENCE_LENGTH>,
+    position: usize,
+    wait_ms: u32,
+    state: SequenceState,
+}
+
+impl Sequencer {
+    pub fn new() -> Self {
+        Sequencer { sequence: heapless::Vec::new(), position: 0, wait_ms: 0, state: SequenceState::Idle }
+    }
+
+    // replace any running sequence, returns the offset of the first invalid byte
+    pub fn start(&mut self, sequence: &[u8]) -> Result<(), usize> {
+        validate(sequence)?;
+        self.sequence = heapless::Vec::from_slice(sequence).map_err(|_| MAX_SEQUENCE_LENGTH)?;
+        self.position = 0;
+        self.wait_ms = 0;
+        self.state = SequenceState::Running;
+        Ok(())
+    }
+
+    pub fn abort(&mut self) {
+        if self.state == SequenceState::Running {
+            self.state = SequenceState::Aborted;
+        }
+    }
+
+    pub fn state(&self) -> SequenceState {
+        self.state
+    }
+
+    // must be called periodically with the elapsed time since the last call, runs the
+    // orders until the next wait or the end of the sequence
+    pub fn tick<C: CTLPinsTrait>(&mut self, ctlpins: &mut C, elapsed_ms: u32) {
+        if self.state != SequenceState::Running {
+            return;
+        }
+        self.wait_ms = self.wait_ms.saturating_sub(elapsed_ms);
+        while self.wait_ms == 0 {
+            // the sequence was validated in start
+            let (order, next) = match parse_order(&self.sequence, self.position) {
+                Ok(Some(o)) => o,
+                _ => {
+                    self.state = SequenceState::Done;
+                    return;
+                }
+            };
+            self.position = next;
+            match order {
+                Order::Pin(b'a', state) => ctlpins.set_ctl_a(state),
+                Order::Pin(b'b', state) => ctlpins.set_ctl_b(state),
+                Order::Pin(b'c', state) => ctlpins.set_ctl_c(state),
+                Order::Pin(b'd', state) => ctlpins.set_ctl_d(state),
+                Order::Pin(_, state) => ctlpins.set_reset(state),
+                Order::Wait(units) => self.wait_ms = units.saturating_mul(WAIT_UNIT_MS),
+                Order::Power(true) => ctlpins.power_on(&[]),
+                Order::Power(false) => ctlpins.power_off(&[]),
+            }
+        }
+    }
+
+    // progress for the control interface, all values little endian:
+    //   0 u8  0 idle, 1 running, 2 done, 3 aborted
+    //   1 u16 offset of the next order in the sequence
+    //   3 u16 length of the sequence
+    pub fn status(&self) -> heapless::Vec<u8, 5> {
+        let mut buf = heapless::Vec::<u8, 5>::new();
+        buf.push(match self.state {
+            SequenceState::Idle => 0,
+            SequenceState::Running => 1,
+            SequenceState::Done => 2,
+            SequenceState::Aborted => 3,
+        }).ok();
+        buf.extend_from_slice(&(self.position as u16).to_le_bytes()).ok();
+        buf.extend_from_slice(&(self.sequence.len() as u16).to_le_bytes()).ok();
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // records the orders as they would appear in a sequence
+    #[derive(Default)]
+    struct Recorder {
+        orders: Vec<String>,
+    }
+
+    impl Recorder {
+        fn pin(&mut self, pin: char, state: PinState) {
+            self.orders.push(format!("{}{}", pin, state.as_u8() as char));
+        }
+    }
+
+    impl CTLPinsTrait for Recorder {
+        fn set_ctl_a(&mut self, state: PinState) { self.pin('a', state) }
+        fn set_ctl_b(&mut self, state: PinState) { self.pin('b', state) }
+        fn set_ctl_c(&mut self, state: PinState) { self.pin('c', state) }
+        fn set_ctl_d(&mut self, state: PinState) { self.pin('d', state) }
+        fn set_reset(&mut self, state: PinState) { self.pin('r', state) }
+        fn power_on(&mut self, _: &[u8]) { self.orders.push("p1".into()) }
+        fn power_off(&mut self, _: &[u8]) { self.orders.push("p0".into()) }
+        fn get_pins(&self) -> [PinState; 5] { [PinState::Floating; 5] }
+        fn sense_pins(&self) -> [bool; 5] { [false; 5] }
+        fn is_on(&self) -> bool { false }
+        fn sequence_count(&self) -> u32 { 0 }
+    }
+
+    #[test]
+    fn valid_sequences() {
+        for sequence in ["", ",,", "p1,aL,rL,w1,rZ,w1", "P0BhCzDl", "w0,w4294967296", "ah\0garbage"].iter() {
+            assert_eq!(validate(sequence.as_bytes()), Ok(()), "{}", sequence);
+        }
+    }
+
+    #[test]
+    fn invalid_offsets() {
+        assert_eq!(validate(b"x"), Err(0));
+        assert_eq!(validate(b"p1,aq"), Err(4));
+        assert_eq!(validate(b"p1,a"), Err(4));
+        assert_eq!(validate(b"p2"), Err(1));
+        assert_eq!(validate(b"aL,w,bZ"), Err(4));
+        assert_eq!(validate(b"aL bZ"), Err(2));
+    }
+
+    #[test]
+    fn start_rejects_invalid() {
+        let mut sequencer = Sequencer::new();
+        assert_eq!(sequencer.start(b"aL,x"), Err(3));
+        assert!(sequencer.state() == SequenceState::Idle);
+        assert_eq!(sequencer.start(&[b','; MAX_SEQUENCE_LENGTH + 1]), Err(MAX_SEQUENCE_LENGTH));
+    }
+
+    #[test]
+    fn runs_until_each_wait() {
+        let mut sequencer = Sequencer::new();
+        let mut pins = Recorder::default();
+        sequencer.start(b"p1,aL,w2,rZ,w1,bH,p0").unwrap();
+        sequencer.tick(&mut pins, 0);
+        assert_eq!(pins.orders, ["p1", "al"]);
+        sequencer.tick(&mut pins, 150);
+        assert_eq!(pins.orders.len(), 2);
+        sequencer.tick(&mut pins, 50);
+        assert_eq!(pins.orders, ["p1", "al", "rz"]);
+        assert_eq!(&sequencer.status()[..], [1, 14, 0, 20, 0]);
+        sequencer.tick(&mut pins, 100);
+        assert_eq!(pins.orders, ["p1", "al", "rz", "bh", "p0"]);
+        assert!(sequencer.state() == SequenceState::Done);
+    }
+
+    #[test]
+    fn abort() {
+        let mut sequencer = Sequencer::new();
+        let mut pins = Recorder::default();
+        sequencer.start(b"aL,w1,aH").unwrap();
+        sequencer.tick(&mut pins, 0);
+        sequencer.abort();
+        sequencer.tick(&mut pins, 100);
+        assert_eq!(pins.orders, ["al"]);
+        assert!(sequencer.state() == SequenceState::Aborted);
+    }
+}
@@ -4,25 +4,38 @@ use core::fmt::Write;
 
 use arrayvec::ArrayString;
 
-use crate::config::ConfigArea;
+use crate::config::{value_bytes, ConfigArea, ConfigError, ERASE_WARNING_THRESHOLD, FACTORY_RESET_TOKEN, LOCK_SECRET_LENGTH};
 use crate::ctlpins::{PinState, CTLPinsTrait};
-use crate::powermeter::PowerMeter;
+use crate::powermeter::{PowerMeter, PowerReadings};
 use crate::{usbserial::*, ctlpins::CTLPins};
 use crate::storage::StorageSwitchTrait;
 use crate::version;
+use crate::dfu;
+use crate::clock;
+use crate::json;
+use crate::lease::Lease;
+use crate::status::{DeviceStatus, FAULT_CONFIG_WRITE, FAULT_NAMES};
+use crate::profiles::{AUTOEXEC_LENGTH, PROFILE_NAME_LENGTH};
+use crate::script::{self, Batch, BootLog, Condition, Quantity, CONDITION_USAGE, MAX_SLEEP_MS};
+use crate::reply::{Reply, Value, CODE_FAILED, CODE_USAGE, CODE_UNSUPPORTED};
+use crate::tokenizer::{TokenError, Tokenizer};
 
 use ushell::{
     autocomplete::StaticAutocomplete, history::LRUHistory, Input as ushell_input,
     ShellError as ushell_error, UShell,
 };
-const N_COMMANDS: usize = 14;
+const N_COMMANDS: usize = 25;
 const COMMANDS: [&str; N_COMMANDS] = ["help", "about", "get-config", "version", "meter", "storage", "send",
-                                      "set", "set-config", "monitor", "power", "console", "status", "clear"];
+                                      "set", "set-config", "monitor", "power", "console", "status", "clear",
+                                      "factory-reset", "config", "profile", "reboot", "bootloader", "time",
+                                      "format", "sleep", "if", "expect", "autoexec"];
 pub type ShellType = UShell<USBSerialType, StaticAutocomplete<N_COMMANDS>, LRUHistory<512, 10>, 512>;
 pub struct ShellStatus {
     pub monitor_enabled: bool,
     pub meter_enabled: bool,
     pub console_mode: bool,
+    pub json: bool, // replies as JSON objects, see reply.rs
+    pub batch: Batch, // commands of the last command line, see script.rs
 }
 
 pub const SHELL_PROMPT: &str = "#> ";
@@ -30,18 +43,33 @@ pub const CR: &str = "\r\n";
 
 pub const HELP: &str = "\r\n\
         about               : print information about this device\r\n\
+        autoexec [log]      : print the boot script, or the output of its last run\r\n\
+        autoexec [-s secret] set \"cmd; cmd ...\"|clear : store the boot script, pressing the button at power up skips it\r\n\
+        bootloader          : power off the DUT and restart into the DFU bootloader\r\n\
         clear               : clear the screen\r\n\
+        cmd; cmd ...        : run commands in a row, a new command line stops the ones left\r\n\
+        format [json|text]  : print or set the output format, --json or --text after a command sets it for that command,\r\n\
+                              except for autoexec, profile, send and set-config\r\n\
+        expect condition    : stop the batch unless the condition holds\r\n\
+        factory-reset erase-config [secret] : erase the config in flash and restore the defaults\r\n\
         help                : print this help\r\n\
+        if condition cmd    : run cmd when current|voltage|power <|<=|>|>= value, or pin r|a|b|c|d low|high\r\n\
         meter on|read|off   : read power consumption\r\n\
         monitor on|off      : enable or disable the serial console monitor in this terminal\r\n\
+        config stats        : print config flash wear statistics\r\n\
+        config lock|unlock secret : lock or unlock config writes with a secret\r\n\
         console             : enter into serial console mode, exit with CTRL+A 5 times\r\n\
         power on|off        : power on or off the DUT\r\n\
+        profile list|[-s secret] save|load|delete name : manage the stored DUT profiles\r\n\
+        reboot              : power off the DUT and restart this device\r\n\
         send string         : send string to the DUT\r\n\
+        sleep ms            : wait before the next command of the batch\r\n\
         set r|a|b|c|d l|h|z : set RESET, CTL_A,B,C or D to low, high or high impedance\r\n\
-        set-config name|tags|json|usb_console|poweron|poweroff value : set the config value in flash\r\n\
-        get-config          : print all the config parameters\r\n\
-        status              : print status of the device\r\n\
+        set-config [-s secret] name|tags|json|usb_console|power_on|power_off|power_rescue value : set the config value in flash\r\n\
+        get-config [key|json.path] : print all the config parameters, one, or a value in the json\r\n\
+        status              : print power, storage, pins, readings, console, lease and faults\r\n\
         storage dut|host|off: connect storage to DUT, host or disconnect\r\n\
+        time [set|sync seconds[.ms]|drift ppm] : print or set the epoch time, sync also corrects the drift\r\n\
         version             : print version information\r\n\
         ";
 
@@ -53,6 +81,19 @@ pub const ABOUT_CONTINUATION: &str = "\r\n\r\n\
           more information can be found here:\r\n\r\n\
               https://github.com/redhat-et/jumpstarter\r\n\
         ";
+type SecretString = ArrayString<LOCK_SECRET_LENGTH>;
+
+// an optional leading "-s secret" supplies the unlock secret for a locked config,
+// returns the secret and the next word
+fn next_token_with_secret<const N: usize>(tokens: &mut Tokenizer) -> Result<(Option<SecretString>, Option<ArrayString<N>>), TokenError> {
+    let word = tokens.next_token::<N>()?;
+    if word.as_deref() == Some("-s") {
+        let secret = tokens.next_token::<LOCK_SECRET_LENGTH>()?;
+        return Ok((secret, tokens.next_token::<N>()?));
+    }
+    Ok((None, word))
+}
+
 pub fn new(serial:USBSerialType) -> ShellType {
     let autocomplete = StaticAutocomplete(COMMANDS);
     let history = LRUHistory::default();
@@ -67,63 +108,408 @@ pub fn handle_shell_commands<L, S, P>(shell: &mut ShellType,
                                       ctl_pins:&mut CTLPins<P>,
                                       send_to_dut: &mut dyn FnMut(&[u8]),
                                       power_meter: &mut dyn PowerMeter,
-                                      config: &mut ConfigArea)
+                                      config: &mut ConfigArea,
+                                      lease: &Lease,
+                                      device_status: &DeviceStatus,
+                                      boot_log: &mut BootLog)
 where
     L: OutputPin,
     S: StorageSwitchTrait,
     P: OutputPin,
 {
     loop {
-        let mut response = ArrayString::<512>::new();
-        write!(response, "{0:}", CR).ok();
-
         let result = shell.poll();
 
         match result {
             Ok(Some(ushell_input::Command((cmd, args)))) => {
                 led_cmd.set_low().ok();
-                match cmd {
-                        "about" =>      { write!(response, "{}", ABOUT).ok();
-                                          version::write_version(&mut response);
-                                          write!(response, "{}", ABOUT_CONTINUATION).ok();
-                                        }
-                        "help" =>       { shell.write_str(HELP).ok(); }
-                        "clear" =>      { shell.clear().ok(); }
-                        "console" =>    { handle_console_cmd(&mut response, args, shell_status); }
-                        "monitor" =>    { handle_monitor_cmd(&mut response, args, shell_status); }
-                        "meter" =>      { handle_meter_cmd(&mut response, args, shell_status, power_meter); }
-                        "storage" =>    { handle_storage_cmd(&mut response, args, storage); }
-                        "power" =>      { handle_power_cmd(&mut response, args, ctl_pins, config); }
-                        "send" =>       { handle_send_cmd(&mut response, args, send_to_dut); }
-                        "set" =>        { handle_set_cmd(&mut response, args, ctl_pins); }
-                        "set-config" => { handle_set_config_cmd(&mut response, args, config); }
-                        "get-config" => { handle_get_config_cmd(&mut response, args, config); }
-                        "status" =>     { handle_status_cmd(&mut response, args, shell_status); }
-                        "version" =>    { version::write_version(&mut response); }
-                        "" =>           {}
-                        _ =>            { write!(shell, "{0:}unsupported command{0:}", CR).ok(); }
+                shell.write_str(CR).ok();
+                if shell_status.batch.is_running() {
+                    let capture = shell_status.batch.is_captured();
+                    let mut response = Reply::new();
+                    write!(response.fail(CODE_FAILED), "Batch aborted by a new command line").ok();
+                    write_reply(output(shell, boot_log, capture), "batch", &response, shell_status.json);
                 }
-                // If response was added complete with an additional CR
-                if response.len() > 2 {
-                    write!(response, "{0:}", CR).ok();
+                if !shell_status.batch.start(cmd, args) {
+                    let mut response = Reply::new();
+                    write!(response.fail(CODE_USAGE), "Command line too long").ok();
+                    write_reply(shell, "batch", &response, shell_status.json);
+                    write_prompt(shell, shell_status);
                 }
+                run_batch(shell, shell_status, storage, ctl_pins, send_to_dut, power_meter, config, lease, device_status, boot_log);
+            }
+            Err(ushell_error::WouldBlock) => break,
+            _ => {}
+        }
+    }
+    // a sleeping batch resumes here, the periodic task pends the USB interrupt when it is due
+    run_batch(shell, shell_status, storage, ctl_pins, send_to_dut, power_meter, config, lease, device_status, boot_log);
+}
+
+// the prompt is left out in json mode, where every line is a JSON object
+fn write_prompt(shell: &mut ShellType, shell_status: &ShellStatus) {
+    if !shell_status.json {
+        shell.write_str(SHELL_PROMPT).ok();
+    }
+}
+
+// CTRL+B pressed 5 times in console mode, reported like the reply of a command
+pub fn exit_console_mode(shell: &mut ShellType, shell_status: &mut ShellStatus) {
+    shell_status.console_mode = false;
+    shell_status.monitor_enabled = false;
+    let mut response = Reply::new();
+    write!(response, "Exiting console mode").ok();
+    shell.write_str(CR).ok();
+    write_reply(shell, "console", &response, shell_status.json);
+    write_prompt(shell, shell_status);
+}
+
+// the boot script runs in console mode too, where the shell doesn't read commands
+pub fn handle_boot_script<S, P>(shell: &mut ShellType,
+                                shell_status: &mut ShellStatus,
+                                storage: &mut S,
+                                ctl_pins:&mut CTLPins<P>,
+                                send_to_dut: &mut dyn FnMut(&[u8]),
+                                power_meter: &mut dyn PowerMeter,
+                                config: &mut ConfigArea,
+                                lease: &Lease,
+                                device_status: &DeviceStatus,
+                                boot_log: &mut BootLog)
+where
+    S: StorageSwitchTrait,
+    P: OutputPin,
+{
+    if shell_status.batch.is_captured() {
+        run_batch(shell, shell_status, storage, ctl_pins, send_to_dut, power_meter, config, lease, device_status, boot_log);
+    }
+}
+
+// pressing the button stops the boot script, the batch ends on the next run_batch
+pub fn stop_boot_script(shell_status: &mut ShellStatus, boot_log: &mut BootLog) {
+    if shell_status.batch.is_captured() && shell_status.batch.is_running() {
+        shell_status.batch.abort();
+        write!(boot_log, "Boot script stopped by the button{}", CR).ok();
+    }
+}
+
+fn run_batch<S, P>(shell: &mut ShellType,
+                   shell_status: &mut ShellStatus,
+                   storage: &mut S,
+                   ctl_pins:&mut CTLPins<P>,
+                   send_to_dut: &mut dyn FnMut(&[u8]),
+                   power_meter: &mut dyn PowerMeter,
+                   config: &mut ConfigArea,
+                   lease: &Lease,
+                   device_status: &DeviceStatus,
+                   boot_log: &mut BootLog)
+where
+    S: StorageSwitchTrait,
+    P: OutputPin,
+{
+    while shell_status.batch.is_due(crate::uptime_ms()) {
+        let capture = shell_status.batch.is_captured();
+        match shell_status.batch.next() {
+            Some(command) => {
+                if capture {
+                    write!(boot_log, "> {}{}", command.trim(), CR).ok();
+                }
+                let (cmd, args) = split_command(&command);
+                run_command(shell, shell_status, storage, ctl_pins, send_to_dut, power_meter, config, lease, device_status, boot_log,
+                            capture, cmd, args);
+                // the commands left would be typed into the DUT console otherwise
+                if shell_status.console_mode && !capture {
+                    shell_status.batch.abort();
+                }
+            }
+            None => {
                 // if console mode has been entered we should not print the SHELL PROMPT again
-                if !shell_status.console_mode {
-                    write!(response, "{}", SHELL_PROMPT).ok();
+                if !shell_status.console_mode && !capture {
+                    write_prompt(shell, shell_status);
                 }
-                shell.write_str(&response).ok();
+            }
+        }
+    }
+}
+
+fn run_command<'a, S, P>(shell: &mut ShellType,
+                     shell_status: &mut ShellStatus,
+                     storage: &mut S,
+                     ctl_pins:&mut CTLPins<P>,
+                     send_to_dut: &mut dyn FnMut(&[u8]),
+                     power_meter: &mut dyn PowerMeter,
+                     config: &mut ConfigArea,
+                     lease: &Lease,
+                     device_status: &DeviceStatus,
+                     boot_log: &mut BootLog,
+                     capture: bool,
+                     mut cmd: &'a str,
+                     mut args: &'a str)
+where
+    S: StorageSwitchTrait,
+    P: OutputPin,
+{
+    let mut response = Reply::new();
+    let mut json = shell_status.json;
+
+    // if runs its command in place of itself, or nothing when the condition doesn't hold
+    while cmd == "if" {
+        match Condition::parse(args) {
+            Some((condition, rest)) if rest != "" => {
+                let (readings, pins) = (power_meter.readings(), ctl_pins.sense_pins());
+                let (c, a) = split_command(rest);
+                if !condition.holds(&readings, &pins) {
+                    // the format flag of the skipped command applies to this reply
+                    json = split_format_flag(c, a, json).1;
+                    write!(response, "Condition not met, skipped: ").ok();
+                    write_condition_value(&mut response, &condition, &readings, &pins);
+                    // data: "taken" false and the "value" tested, the reply of the command
+                    // replaces this one when the condition holds
+                    response.field("taken", Value::Bool(false));
+                    break;
+                }
+                cmd = c;
+                args = a;
+            },
+            _ => {
+                write!(response.fail(CODE_USAGE), "usage: if {} command", CONDITION_USAGE).ok();
+                break;
+            },
+        }
+    }
+    if cmd != "if" {
+        (args, json) = split_format_flag(cmd, args, json);
+    }
 
+    match cmd {
+            "about" =>      { write!(response, "{}", ABOUT).ok();
+                              version::write_version(&mut response);
+                              write!(response, "{}", ABOUT_CONTINUATION).ok();
+                            }
+            "help" =>       { if json {
+                                  // data: "commands", the names of all the commands
+                                  response.open(Some("commands"), '[');
+                                  for c in COMMANDS.iter() {
+                                      response.element(Value::Str(c));
+                                  }
+                                  response.close(']');
+                              } else {
+                                  output(shell, boot_log, capture).write_str(HELP).ok();
+                              }
+                            }
+            "clear" =>      { if !capture {
+                                  shell.clear().ok();
+                              }
+                            }
+            "console" =>    { handle_console_cmd(&mut response, args, shell_status); }
+            "monitor" =>    { handle_monitor_cmd(&mut response, args, shell_status); }
+            "meter" =>      { handle_meter_cmd(&mut response, args, shell_status, power_meter); }
+            "storage" =>    { handle_storage_cmd(&mut response, args, storage); }
+            "power" =>      { handle_power_cmd(&mut response, args, ctl_pins, config); }
+            "send" =>       { handle_send_cmd(&mut response, args, send_to_dut); }
+            "set" =>        { handle_set_cmd(&mut response, args, ctl_pins); }
+            "set-config" => { handle_set_config_cmd(&mut response, args, config); }
+            "get-config" => { handle_get_config_cmd(&mut response, args, config); }
+            "factory-reset" => { handle_factory_reset_cmd(&mut response, args, config); }
+            "config" =>     { handle_config_cmd(&mut response, args, config); }
+            "profile" =>    { handle_profile_cmd(&mut response, args, config, ctl_pins); }
+            "status" =>     { handle_status_cmd(&mut response, args, shell_status, lease, device_status,
+                                                ctl_pins, storage, power_meter, config); }
+            "version" =>    { handle_version_cmd(&mut response, args); }
+            "time" =>       { handle_time_cmd(&mut response, args); }
+            "format" =>     { handle_format_cmd(&mut response, args, shell_status);
+                              json = shell_status.json;
+                            }
+            "sleep" =>      { handle_sleep_cmd(&mut response, args, shell_status); }
+            "expect" =>     { handle_expect_cmd(&mut response, args, shell_status, ctl_pins, power_meter); }
+            "autoexec" =>   { handle_autoexec_cmd(&mut response, args, shell_status, config, boot_log); }
+            "if" =>         {}
+            "reboot" | "bootloader" => {
+                if args == "" {
+                    // the rest of the batch doesn't run, the reboot follows this reply
+                    shell_status.batch.abort();
+                    dfu::request_reboot(cmd == "bootloader");
+                    write!(response, "Powering off the DUT and restarting").ok();
+                } else {
+                    response.usage(cmd);
+                }
             }
-            Err(ushell_error::WouldBlock) => break,
-            _ => {}
+            _ =>            { write!(response.fail(CODE_UNSUPPORTED), "unsupported command").ok(); }
+    }
+    write_reply(output(shell, boot_log, capture), cmd, &response, json);
+}
+
+// command name and arguments of a batch command
+fn split_command(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(p) => (&line[..p], line[p..].trim()),
+        None => (line, ""),
+    }
+}
+
+// the boot script writes to the boot log, everything else to the shell
+fn output<'a>(shell: &'a mut ShellType, boot_log: &'a mut BootLog, capture: bool) -> &'a mut dyn Write {
+    if capture {
+        boot_log
+    } else {
+        shell
+    }
+}
+
+// text mode: the message on its own lines, json mode: the reply as one JSON object per line
+fn write_reply(out: &mut dyn Write, cmd: &str, response: &Reply, json: bool) {
+    if json {
+        response.write_json(cmd, out);
+        out.write_str(CR).ok();
+    } else if !response.is_empty() {
+        response.write_text(out);
+        out.write_str(CR).ok();
+    }
+}
+
+fn handle_autoexec_cmd(response:&mut Reply, args: &str, shell_status: &ShellStatus, config: &mut ConfigArea, boot_log: &BootLog) {
+    let mut tokens = Tokenizer::new(args);
+    let parsed = next_token_with_secret::<8>(&mut tokens)
+        .and_then(|(secret, sub)| Ok((secret, sub, tokens.remainder::<AUTOEXEC_LENGTH>()?)));
+    let (secret, sub, script) = match parsed {
+        Ok((secret, sub, script)) => (secret, sub.unwrap_or_default(), script),
+        Err(e) => {
+            write!(response.fail(CODE_USAGE), "autoexec: {}", e.as_str()).ok();
+            return;
+        },
+    };
+    let secret = secret.as_ref().map(|s| s.as_bytes());
+    let (sub, script) = (sub.as_str(), script.as_str());
+
+    if sub == "" && secret.is_none() {
+        let stored = config.autoexec();
+        if stored.is_empty() {
+            write!(response, "no boot script stored").ok();
+        } else {
+            write_u8(response, stored);
         }
+        // data: "script", empty when none is stored
+        response.field("script", Value::Bytes(stored));
+    } else if sub == "log" && script.is_empty() && secret.is_none() {
+        write!(response, "{}", boot_log.as_str().trim_end()).ok();
+        // data: "running" while the boot script hasn't finished, and "truncated" when the
+        // log lost the end of its output, the log itself is the message
+        response.field("running", Value::Bool(shell_status.batch.is_captured() && shell_status.batch.is_running()));
+        response.field("truncated", Value::Bool(boot_log.is_truncated()));
+    } else if (sub == "set" && !script.is_empty()) || (sub == "clear" && script.is_empty()) {
+        match config.set_autoexec(script.as_bytes(), secret) {
+            Ok(()) if script.is_empty() => write!(response, "Boot script cleared").ok(),
+            Ok(()) => write!(response, "Boot script stored, it runs at the next boot").ok(),
+            Err(e) => write!(response.fail(CODE_FAILED), "Failed to store the boot script: {}", e.as_str()).ok(),
+        };
+    } else {
+        response.usage("autoexec [log]|[-s secret] set \"cmd; cmd ...\"|clear");
     }
 }
 
-fn handle_power_cmd<B, C>(response:&mut B, args: &str, ctlpins: &mut C, config: &ConfigArea)
+fn handle_sleep_cmd(response:&mut Reply, args: &str, shell_status: &mut ShellStatus) {
+    match args.parse::<u32>() {
+        Ok(ms) if ms <= MAX_SLEEP_MS => {
+            shell_status.batch.sleep(ms, crate::uptime_ms());
+            // data: "ms" slept before the next command
+            response.field("ms", Value::Int(ms as i64));
+        },
+        _ => { write!(response.fail(CODE_USAGE), "usage: sleep ms, up to {}", MAX_SLEEP_MS).ok(); },
+    }
+}
+
+fn handle_expect_cmd<C>(response:&mut Reply, args: &str, shell_status: &mut ShellStatus, ctl_pins: &mut C, power_meter: &mut dyn PowerMeter)
 where
-    C: CTLPinsTrait,
-    B: Write
+    C: CTLPinsTrait
+ {
+    let condition = match Condition::parse(args) {
+        Some((condition, "")) => condition,
+        _ => {
+            write!(response.fail(CODE_USAGE), "usage: expect {}", CONDITION_USAGE).ok();
+            return;
+        },
+    };
+    let (readings, pins) = (power_meter.readings(), ctl_pins.sense_pins());
+    let passed = condition.holds(&readings, &pins);
+    if passed {
+        write!(response, "Expectation met: ").ok();
+    } else {
+        shell_status.batch.abort();
+        write!(response.fail(CODE_FAILED), "Expectation failed, batch stopped: ").ok();
+    }
+    write_condition_value(response, &condition, &readings, &pins);
+    // data: "passed" and the "value" tested
+    response.field("passed", Value::Bool(passed));
+}
+
+fn write_condition_value(response:&mut Reply, condition: &Condition, readings: &PowerReadings, pins: &[bool; 5]) {
+    match *condition {
+        Condition::Meter(quantity, _, _) => {
+            let value = script::measure(quantity, readings);
+            let (name, unit) = match quantity {
+                Quantity::Current => ("current", "A"),
+                Quantity::Voltage => ("voltage", "V"),
+                Quantity::Power => ("power", "W"),
+            };
+            write!(response, "{} {:.3}{}", name, value, unit).ok();
+            response.field("value", Value::Float(value));
+        },
+        Condition::Pin(pin, _) => {
+            let name = ["/RESET", "CTL_A", "CTL_B", "CTL_C", "CTL_D"][pin];
+            write!(response, "{} {}", name, if pins[pin] { "high" } else { "low" }).ok();
+            response.field("value", Value::Bool(pins[pin]));
+        },
+    }
+}
+
+// commands whose last argument takes the rest of the line, a trailing --json or --text is part of it
+const FREE_FORM_COMMANDS: [&str; 4] = ["autoexec", "profile", "send", "set-config"];
+
+// a trailing --json or --text selects the output format of a single command
+fn split_format_flag<'a>(cmd: &str, args: &'a str, json: bool) -> (&'a str, bool) {
+    if FREE_FORM_COMMANDS.contains(&cmd) {
+        return (args, json);
+    }
+    let trimmed = args.trim_end();
+    for &(flag, flag_json) in [("--json", true), ("--text", false)].iter() {
+        if let Some(rest) = trimmed.strip_suffix(flag) {
+            if rest.is_empty() || rest.ends_with(' ') {
+                return (rest.trim_end(), flag_json);
+            }
+        }
+    }
+    (args, json)
+}
+
+fn handle_format_cmd(response:&mut Reply, args: &str, shell_status: &mut ShellStatus) {
+    if args == "json" {
+        shell_status.json = true;
+    } else if args == "text" {
+        shell_status.json = false;
+    } else if args != "" {
+        response.usage("format [json|text]");
+        return;
+    }
+    let format = if shell_status.json { "json" } else { "text" };
+    write!(response, "Output format: {}", format).ok();
+    // data: "format" of this session, json or text
+    response.field("format", Value::Str(format));
+}
+
+fn handle_version_cmd(response:&mut Reply, args: &str) {
+    if args != "" {
+        response.usage("version");
+        return;
+    }
+    version::write_version(response);
+    // data: "version" and "git_ref"
+    response.field("version", Value::Str(version::version()));
+    response.field("git_ref", Value::Str(version::git_ref()));
+}
+
+fn handle_power_cmd<C>(response:&mut Reply, args: &str, ctlpins: &mut C, config: &ConfigArea)
+where
+    C: CTLPinsTrait
  {
     if args == "on" {
         ctlpins.power_on(&config.get().power_on);
@@ -141,25 +527,68 @@ where
         ctlpins.power_on(&config.get().power_rescue);
         write!(response, "Device powered on to rescue").ok();
     } else {
-        write!(response, "usage: power on|off|force-on|force-off|rescue").ok();
+        response.usage("power on|off|force-on|force-off|rescue");
+        return;
     }
+    // data: "power" true when the DUT is powered
+    response.field("power", Value::Bool(ctlpins.is_on()));
 }
 
-fn handle_send_cmd<B>(response:&mut B, args: &str, send_to_dut: &mut dyn FnMut(&[u8]))
-where
-    B: Write
- {
-    if args.len() > 0 {
-        send_to_dut(args.as_bytes());
-    } else {
-        write!(response, "usage: send string").ok();
+fn handle_time_cmd(response:&mut Reply, args: &str) {
+    let mut tokens = args.split_whitespace();
+    match (tokens.next(), tokens.next(), tokens.next()) {
+        (None, _, _) => {
+            match clock::wall_ms() {
+                Some(t) => {
+                    let (year, month, day, hour, minute, second, ms) = clock::to_utc(t);
+                    write!(response, "{}-{:02}-{:02} {:02}:{:02}:{:02}.{:03} UTC ({}.{:03}), drift {} ppm",
+                           year, month, day, hour, minute, second, ms, t / 1000, ms, clock::drift_ppm()).ok();
+                },
+                None => {
+                    let uptime = crate::uptime_ms();
+                    write!(response, "Time not set, uptime {}.{:03} s", uptime / 1000, uptime % 1000).ok();
+                },
+            };
+        },
+        (Some("set"), Some(t), None) if clock::parse_epoch_ms(t).is_some() => {
+            let correction = clock::set(clock::parse_epoch_ms(t).unwrap_or(0));
+            write!(response, "Time set, stepped by {} ms", correction).ok();
+        },
+        (Some("sync"), Some(t), None) if clock::parse_epoch_ms(t).is_some() => {
+            match clock::sync(clock::parse_epoch_ms(t).unwrap_or(0)) {
+                Ok(correction) => write!(response, "Time synced, stepped by {} ms, drift {} ppm", correction, clock::drift_ppm()).ok(),
+                Err(clock::TimeError::NotSet) => write!(response.fail(CODE_FAILED), "Time not set, use time set first").ok(),
+                Err(clock::TimeError::TooSoon) => write!(response.fail(CODE_FAILED), "Too soon since the last sync to estimate the drift").ok(),
+            };
+        },
+        (Some("drift"), Some(ppm), None) if ppm.parse::<i32>().is_ok() => {
+            match clock::set_drift(ppm.parse().unwrap_or(0)) {
+                Ok(()) => write!(response, "Drift set to {} ppm", clock::drift_ppm()).ok(),
+                Err(_) => write!(response.fail(CODE_FAILED), "Time not set, use time set first").ok(),
+            };
+        },
+        _ => {
+            response.usage("time [set|sync seconds[.ms]|drift ppm]");
+            return;
+        },
+    }
+    // data: "time_ms" epoch time or null when not set, "uptime_ms", "drift_ppm"
+    response.field("time_ms", clock::wall_ms().map_or(Value::Null, |t| Value::Int(t as i64)));
+    response.field("uptime_ms", Value::Int(crate::uptime_ms() as i64));
+    response.field("drift_ppm", Value::Int(clock::drift_ppm() as i64));
+}
+
+fn handle_send_cmd(response:&mut Reply, args: &str, send_to_dut: &mut dyn FnMut(&[u8])) {
+    match Tokenizer::new(args).remainder::<512>() {
+        Ok(data) if data.len() > 0 => send_to_dut(data.as_bytes()),
+        Ok(_) => response.usage("send string"),
+        Err(e) => { write!(response.fail(CODE_USAGE), "send: {}", e.as_str()).ok(); },
     }
 }
 
-fn handle_storage_cmd<B,S>(response:&mut B, args: &str, storage: &mut S)
+fn handle_storage_cmd<S>(response:&mut Reply, args: &str, storage: &mut S)
 where
-    S: StorageSwitchTrait,
-    B: Write
+    S: StorageSwitchTrait
  {
     if args == "dut" {
         storage.connect_to_dut();
@@ -171,31 +600,37 @@ where
         storage.power_off();
         write!(response, "storage disconnected").ok();
     } else {
-        write!(response, "usage: storage dut|host|off").ok();
+        response.usage("storage dut|host|off");
+        return;
     }
+    // data: "storage" dut, host or off
+    response.field("storage", Value::Str(args));
 }
 
-fn handle_meter_cmd<B>(response:&mut B, args: &str, shell_status: &mut ShellStatus, power_meter: &mut dyn PowerMeter)
-where
-    B: Write
- {
+fn handle_meter_cmd(response:&mut Reply, args: &str, shell_status: &mut ShellStatus, power_meter: &mut dyn PowerMeter) {
     if args == "on" {
         shell_status.meter_enabled = true;
         write!(response, "Power meter monitoring enabled").ok();
     } else if args == "read" {
         power_meter.write(response);
+        // data: "current_a", "voltage_v" and "power_w"
+        let readings = power_meter.readings();
+        response.field("current_a", Value::Float(readings.current));
+        response.field("voltage_v", Value::Float(readings.voltage));
+        response.field("power_w", Value::Float(readings.power));
+        return;
     } else if args == "off" {
         shell_status.meter_enabled = false;
         write!(response, "Power monitor disabled").ok();
     } else {
-        write!(response, "usage: meter on|read|off").ok();
+        response.usage("meter on|read|off");
+        return;
     }
+    // data: "meter" true when the periodic readings are enabled
+    response.field("meter", Value::Bool(shell_status.meter_enabled));
 }
 
-fn handle_monitor_cmd<B>(response:&mut B, args: &str, shell_status: &mut ShellStatus)
-where
-    B: Write
- {
+fn handle_monitor_cmd(response:&mut Reply, args: &str, shell_status: &mut ShellStatus) {
     if args == "on" {
         shell_status.monitor_enabled = true;
         write!(response, "Monitor enabled").ok();
@@ -203,27 +638,25 @@ where
         shell_status.monitor_enabled = false;
         write!(response, "Monitor disabled").ok();
     } else {
-        write!(response, "usage: monitor on|off").ok();
+        response.usage("monitor on|off");
+        return;
     }
+    // data: "monitor" true when the DUT console is shown in this terminal
+    response.field("monitor", Value::Bool(shell_status.monitor_enabled));
 }
 
-fn handle_console_cmd<B>(response:&mut B, args: &str, shell_status: &mut ShellStatus)
-where
-    B: Write
- {
+fn handle_console_cmd(response:&mut Reply, args: &str, shell_status: &mut ShellStatus) {
     if args =="" {
         shell_status.console_mode = true;
         write!(response, "Entering console mode, type CTRL+B 5 times to exit").ok();
     } else {
-        write!(response, "usage: console").ok();
+        response.usage("console");
     }
 }
 
-fn handle_set_cmd<B, C>(response:&mut B, args: &str, ctl_pins:&mut C)
+fn handle_set_cmd<C>(response:&mut Reply, args: &str, ctl_pins:&mut C)
 where
-    B: Write,
     C: CTLPinsTrait
-
  {
 
     if args.len() == 3 && args.as_bytes()[1] == ' ' as u8{
@@ -275,60 +708,73 @@ where
         };
 
         write!(response, "Set {} to {}", ctl_str, val_str).ok();
+        // data: "pin" r, a, b, c or d and "state" l, h or z, as given
+        response.field("pin", Value::Str(&args[..1]));
+        response.field("state", Value::Str(&args[2..]));
     } else {
         write_set_usage(response)
     }
 }
 
-fn handle_set_config_cmd<B>(response:&mut B, args: &str, config: &mut ConfigArea)
-where
-    B: Write
- {
-    let mut split_args = args.split_ascii_whitespace();
-    let key = split_args.next();
-    let mut val = split_args.next();
+fn handle_set_config_cmd(response:&mut Reply, args: &str, config: &mut ConfigArea) {
+    let mut tokens = Tokenizer::new(args);
+    let parsed = next_token_with_secret::<16>(&mut tokens).and_then(|(secret, key)| {
+        // the json value is taken verbatim, its quotes are part of the json
+        let val = match key.as_deref() {
+            Some("json") => ArrayString::from(tokens.raw_remainder()).map_err(|_| TokenError::TooLong)?,
+            _ => tokens.remainder::<512>()?,
+        };
+        Ok((secret, key, val))
+    });
+    let (secret, key, val) = match parsed {
+        Ok((secret, key, val)) => (secret, key, val),
+        Err(e) => {
+            write!(response.fail(CODE_USAGE), "set-config: {}", e.as_str()).ok();
+            return;
+        },
+    };
+    let secret = secret.as_ref().map(|s| s.as_bytes());
     let mut usage = false;
 
-    // empty argument = clear
-    if val == None {
-        val = Some("");
-    }
-
-    if let (Some(k), Some(v)) = (key, val) {
+    // an empty value clears the key
+    if let (Some(k), v) = (key.as_deref(), val.as_str()) {
         let cfg = config.get();
         if k == "name" {
-            let cfg = cfg.set_name(v.as_bytes());
-            config.write_config(&cfg).ok();
-            write!(response, "Set name to {}", v).ok();
+            let result = cfg.set_name(v.as_bytes()).and_then(|cfg| config.write_config_with_secret(&cfg, secret));
+            write_set_config_result(response, k, v, result);
 
         } else if k == "tags" {
-            let cfg = cfg.set_tags(v.as_bytes());
-            write!(response, "Set tags to {}", v).ok();
-            config.write_config(&cfg).ok();
+            let result = cfg.set_tags(v.as_bytes()).and_then(|cfg| config.write_config_with_secret(&cfg, secret));
+            write_set_config_result(response, k, v, result);
 
         } else if k == "json" {
-            let cfg = cfg.set_json(v.as_bytes());
-            write!(response, "Set json to {}", v).ok();
-            config.write_config(&cfg).ok();
+            // an empty value clears the json
+            match json::validate(v.as_bytes()) {
+                Err(e) if !v.is_empty() => {
+                    write!(response.fail(CODE_FAILED), "Invalid json at offset {}, config not changed", e.offset()).ok();
+                },
+                _ => {
+                    let result = cfg.set_json(v.as_bytes()).and_then(|cfg| config.write_config_with_secret(&cfg, secret));
+                    write_set_config_result(response, k, v, result);
+                },
+            }
 
         } else if k == "usb_console" {
-            let cfg = cfg.set_usb_console(v.as_bytes());
-            write!(response, "Set usb_console to {}", v).ok();
-            config.write_config(&cfg).ok();
+            let result = cfg.set_usb_console(v.as_bytes()).and_then(|cfg| config.write_config_with_secret(&cfg, secret));
+            write_set_config_result(response, k, v, result);
 
         } else if k == "power_on" {
-            let cfg = cfg.set_power_on(v.as_bytes());
-            write!(response, "Set power_on to {}", v).ok();
-            config.write_config(&cfg).ok();
+            let result = cfg.set_power_on(v.as_bytes()).and_then(|cfg| config.write_config_with_secret(&cfg, secret));
+            write_set_config_result(response, k, v, result);
 
         } else if k == "power_off" {
-            let cfg = cfg.set_power_off(v.as_bytes());
-            write!(response, "Set power_off to {}", v).ok();
-            config.write_config(&cfg).ok();
+            let result = cfg.set_power_off(v.as_bytes()).and_then(|cfg| config.write_config_with_secret(&cfg, secret));
+            write_set_config_result(response, k, v, result);
+
         } else if k == "power_rescue" {
-            let cfg = cfg.set_power_rescue(v.as_bytes());
-            write!(response, "Set power_rescue to {}", v).ok();
-            config.write_config(&cfg).ok();
+            let result = cfg.set_power_rescue(v.as_bytes()).and_then(|cfg| config.write_config_with_secret(&cfg, secret));
+            write_set_config_result(response, k, v, result);
+
         } else {
             usage = true;
         }
@@ -337,47 +783,204 @@ where
     }
 
     if usage {
-        write!(response, "usage: set-config name|tags|storage|usb_storage value").ok();
+        response.usage("set-config [-s secret] name|tags|json|usb_console|power_on|power_off|power_rescue value");
+    } else if config.stats().wear_warning() {
+        write_wear_warning(response);
     }
 }
 
-fn handle_get_config_cmd<B>(response:&mut B, args: &str, config: &mut ConfigArea)
+fn write_set_config_result(response:&mut Reply, key: &str, val: &str, result: Result<(), ConfigError>) {
+    match result {
+        Ok(()) => write!(response, "Set {} to {}", key, val).ok(),
+        Err(e) => write!(response.fail(CODE_FAILED), "Failed to set {}: {}", key, e.as_str()).ok(),
+    };
+    // data: "key" of the value set
+    response.field("key", Value::Str(key));
+}
+
+fn handle_get_config_cmd(response:&mut Reply, args: &str, config: &mut ConfigArea) {
+    let cfg = config.get();
+    let fields: [(&str, &[u8]); 7] = [
+        ("name", &cfg.name),
+        ("tags", &cfg.tags),
+        ("json", &cfg.json),
+        ("usb_console", &cfg.usb_console),
+        ("power_on", &cfg.power_on),
+        ("power_off", &cfg.power_off),
+        ("power_rescue", &cfg.power_rescue),
+    ];
+
+    // data: the values printed by key, "locked" as a boolean, and for a json path,
+    // "path" and "value" with the JSON text of the value
+    if let Some((key, value)) = fields.iter().find(|(key, _)| *key == args) {
+        write_u8(response, value);
+        response.field(key, Value::Bytes(value));
+    } else if let Some(path) = args.strip_prefix("json.") {
+        match json::query(value_bytes(&cfg.json), path) {
+            Ok(Some(value)) => {
+                write_u8(response, value);
+                response.field("path", Value::Str(path));
+                response.field("value", Value::Bytes(value));
+            },
+            Ok(None) => { write!(response.fail(CODE_FAILED), "json.{} not found", path).ok(); },
+            Err(e) => { write!(response.fail(CODE_FAILED), "stored json is invalid at offset {}", e.offset()).ok(); },
+        }
+    } else if args == "" {
+        for (i, (key, value)) in fields.iter().enumerate() {
+            write!(response, "{}{}: ", if i > 0 { CR } else { "" }, key).ok();
+            write_u8(response, value);
+            response.field(key, Value::Bytes(value));
+        }
+        write!(response, "\r\nlocked: {}", if cfg.is_locked() { "yes" } else { "no" }).ok();
+        response.field("locked", Value::Bool(cfg.is_locked()));
+    } else if args == "locked" {
+        write!(response, "{}", if cfg.is_locked() { "yes" } else { "no" }).ok();
+        response.field("locked", Value::Bool(cfg.is_locked()));
+    } else {
+        response.usage("get-config [name|tags|json|json.path|usb_console|power_on|power_off|power_rescue|locked]");
+    }
+}
+
+fn handle_config_cmd(response:&mut Reply, args: &str, config: &mut ConfigArea) {
+    let mut tokens = Tokenizer::new(args);
+    let (sub, secret) = match (tokens.next_token::<16>(), tokens.next_token::<LOCK_SECRET_LENGTH>()) {
+        (Ok(sub), Ok(secret)) => (sub.unwrap_or_default(), secret.unwrap_or_default()),
+        (Err(e), _) | (_, Err(e)) => {
+            write!(response.fail(CODE_USAGE), "config: {}", e.as_str()).ok();
+            return;
+        },
+    };
+    let (sub, secret) = (sub.as_str(), secret.as_str());
+
+    if sub == "lock" && !secret.is_empty() {
+        match config.lock(secret.as_bytes()) {
+            Ok(()) => write!(response, "Config locked").ok(),
+            Err(e) => write!(response.fail(CODE_FAILED), "Failed to lock config: {}", e.as_str()).ok(),
+        };
+    } else if sub == "unlock" && !secret.is_empty() {
+        match config.unlock(secret.as_bytes()) {
+            Ok(()) => write!(response, "Config unlocked").ok(),
+            Err(e) => write!(response.fail(CODE_FAILED), "Failed to unlock config: {}", e.as_str()).ok(),
+        };
+    } else if sub == "stats" && secret.is_empty() {
+        let stats = config.stats();
+        write!(response, "erase count: {}\r\nwrite count: {}\r\nactive slot: ", stats.erase_count, stats.write_count).ok();
+        match stats.active_slot {
+            Some(i) => write!(response, "{} of {}", i, stats.slots).ok(),
+            None => write!(response, "none").ok(),
+        };
+        write!(response, "\r\nprofile erase count: {}", stats.profile_erase_count).ok();
+        // data: "erase_count", "write_count", "active_slot" or null, "slots", "profile_erase_count"
+        // and "wear_warning"
+        response.field("erase_count", Value::Int(stats.erase_count as i64));
+        response.field("write_count", Value::Int(stats.write_count as i64));
+        response.field("profile_erase_count", Value::Int(stats.profile_erase_count as i64));
+        response.field("active_slot", stats.active_slot.map_or(Value::Null, |i| Value::Int(i as i64)));
+        response.field("slots", Value::Int(stats.slots as i64));
+        response.field("wear_warning", Value::Bool(stats.wear_warning()));
+        if stats.wear_warning() {
+            write_wear_warning(response);
+        }
+    } else {
+        response.usage("config stats|lock secret|unlock secret");
+    }
+}
+
+fn handle_profile_cmd<C>(response:&mut Reply, args: &str, config: &mut ConfigArea, ctl_pins: &mut C)
+where
+    C: CTLPinsTrait
+ {
+    let mut tokens = Tokenizer::new(args);
+    let parsed = next_token_with_secret::<16>(&mut tokens)
+        .and_then(|(secret, sub)| Ok((secret, sub, tokens.remainder::<PROFILE_NAME_LENGTH>()?)));
+    let (secret, sub, name) = match parsed {
+        Ok((secret, sub, name)) => (secret, sub.unwrap_or_default(), name),
+        Err(e) => {
+            write!(response.fail(CODE_USAGE), "profile: {}", e.as_str()).ok();
+            return;
+        },
+    };
+    let secret = secret.as_ref().map(|s| s.as_bytes());
+    let (sub, name) = (sub.as_str(), name.as_str());
+
+    if sub == "list" && name.is_empty() {
+        let active = config.get().active_profile();
+        let mut empty = true;
+        // data: "profiles", an array of objects with "id", "name" and "active"
+        response.open(Some("profiles"), '[');
+        for p in config.profiles().iter() {
+            if !empty {
+                write!(response, "{}", CR).ok();
+            }
+            empty = false;
+            write!(response, "{}: ", p.id()).ok();
+            write_u8(response, &p.name);
+            if active == Some(p.id()) {
+                write!(response, " (active)").ok();
+            }
+            response.open(None, '{');
+            response.field("id", Value::Int(p.id() as i64));
+            response.field("name", Value::Bytes(&p.name));
+            response.field("active", Value::Bool(active == Some(p.id())));
+            response.close('}');
+        }
+        response.close(']');
+        if empty {
+            write!(response, "no profiles saved").ok();
+        }
+    } else if sub == "save" && !name.is_empty() {
+        match config.save_profile(name.as_bytes(), &ctl_pins.get_pins(), secret) {
+            Ok(id) => {
+                // data: "id" of the saved profile
+                response.field("id", Value::Int(id as i64));
+                write!(response, "Saved profile {} with id {}", name, id).ok()
+            },
+            Err(e) => write!(response.fail(CODE_FAILED), "Failed to save profile {}: {}", name, e.as_str()).ok(),
+        };
+    } else if sub == "load" && !name.is_empty() {
+        let id = config.profiles().find(name.as_bytes()).map(|p| p.id());
+        match id.ok_or(ConfigError::NotFound).and_then(|id| config.load_profile(id, secret)) {
+            Ok(pins) => {
+                ctl_pins.set_pins(&pins);
+                write!(response, "Loaded profile {}", name).ok()
+            },
+            Err(e) => write!(response.fail(CODE_FAILED), "Failed to load profile {}: {}", name, e.as_str()).ok(),
+        };
+    } else if sub == "delete" && !name.is_empty() {
+        match config.delete_profile(name.as_bytes(), secret) {
+            Ok(()) => write!(response, "Deleted profile {}", name).ok(),
+            Err(e) => write!(response.fail(CODE_FAILED), "Failed to delete profile {}: {}", name, e.as_str()).ok(),
+        };
+    } else {
+        response.usage("profile list|[-s secret] save|load|delete name");
+    }
+}
+
+fn write_wear_warning<B>(response:&mut B)
 where
     B: Write
  {
-    let cfg = config.get();
+    write!(response, "\r\nWARNING: config or profile sector erased over {} times, flash is wearing out", ERASE_WARNING_THRESHOLD).ok();
+}
 
-    if args == "name" {
-        write_u8(response, &cfg.name);
-    } else if args == "tags" {
-        write_u8(response, &cfg.tags);
-    } else if args == "json" {
-        write_u8(response, &cfg.json);
-    } else if args == "usb_console" {
-        write_u8(response, &cfg.usb_console);
-    } else if args == "power_on" {
-        write_u8(response, &cfg.power_on);
-    } else if args == "power_off" {
-        write_u8(response, &cfg.power_off);
-    } else if args == "power_rescue" {
-        write_u8(response, &cfg.power_rescue);
-    } else if args == "" {
-        write!(response, "name: ").ok();
-        write_u8(response, &cfg.name);
-        write!(response, "\r\ntags: ").ok();
-        write_u8(response, &cfg.tags);
-        write!(response, "\r\njson: ").ok();
-        write_u8(response, &cfg.json);
-        write!(response, "\r\nusb_console: ").ok();
-        write_u8(response, &cfg.usb_console);
-        write!(response, "\r\npower_on: ").ok();
-        write_u8(response, &cfg.power_on);
-        write!(response, "\r\npower_off: ").ok();
-        write_u8(response, &cfg.power_off);
-        write!(response, "\r\npower_rescue: ").ok();
-        write_u8(response, &cfg.power_rescue);
+fn handle_factory_reset_cmd(response:&mut Reply, args: &str, config: &mut ConfigArea) {
+    // the unlock secret is needed as well when the config is locked
+    let mut tokens = Tokenizer::new(args);
+    let (token, secret) = match (tokens.next_token::<16>(), tokens.next_token::<LOCK_SECRET_LENGTH>()) {
+        (Ok(token), Ok(secret)) => (token.unwrap_or_default(), secret),
+        (Err(e), _) | (_, Err(e)) => {
+            write!(response.fail(CODE_USAGE), "factory-reset: {}", e.as_str()).ok();
+            return;
+        },
+    };
+
+    if token.as_str() == FACTORY_RESET_TOKEN && tokens.next_token::<1>() == Ok(None) {
+        match config.factory_reset(secret.as_ref().map(|s| s.as_bytes())) {
+            Ok(()) => write!(response, "Config erased, defaults restored").ok(),
+            Err(e) => write!(response.fail(CODE_FAILED), "Factory reset failed: {}", e.as_str()).ok(),
+        };
     } else {
-        write!(response, "usage: get-config [name|tags|json|usb_console|power_on|power_off|power_rescue]").ok();
+        write!(response.fail(CODE_USAGE), "usage: factory-reset {} [secret]", FACTORY_RESET_TOKEN).ok();
     }
 }
 
@@ -393,20 +996,111 @@ where
     }
 }
 
-fn write_set_usage<B>(response:&mut B)
-where
-    B: Write
- {
-    write!(response, "usage: set r|a|b|c|d l|h|z").ok();
+fn write_set_usage(response:&mut Reply) {
+    response.usage("set r|a|b|c|d l|h|z");
 }
 
-fn handle_status_cmd<B>(response:&mut B, args: &str, shell_status: &mut ShellStatus)
+// same data as the Status request of the control interface, captured now so a batch sees
+// the effect of its previous commands, plus the faults latched by the control interface
+fn handle_status_cmd<S, C>(response:&mut Reply, args: &str, shell_status: &mut ShellStatus, lease: &Lease,
+                           device_status: &DeviceStatus, ctl_pins: &C, storage: &S,
+                           power_meter: &mut dyn PowerMeter, config: &ConfigArea)
 where
-    B: Write
+    S: StorageSwitchTrait,
+    C: CTLPinsTrait
  {
-    if args =="" {
-        write!(response, "Monitor: {}, Meter: {}", shell_status.monitor_enabled, shell_status.meter_enabled).ok();
-    } else {
-        write!(response, "usage: status").ok();
+    if args != "" {
+        response.usage("status");
+        return;
+    }
+    let mut status = DeviceStatus::capture(ctl_pins, storage, &power_meter.readings(), &config.stats());
+    status.set_fault(device_status.faults() & FAULT_CONFIG_WRITE);
+
+    const STORAGE: [&str; 3] = ["off", "host", "dut"];
+    const PIN_NAMES: [&str; 5] = ["/RESET", "CTL_A", "CTL_B", "CTL_C", "CTL_D"];
+    const PIN_STATES: [&str; 3] = ["low", "high", "floating"];
+    let storage_name = STORAGE.get(status.storage() as usize).copied().unwrap_or("?");
+    let (pins, sensed) = (status.pins(), status.sensed());
+    let pin_state = |i: usize| PIN_STATES.get(pins[i] as usize).copied().unwrap_or("?");
+    let pin_sensed = |i: usize| sensed & (1 << i) != 0;
+    let (voltage_mv, current_ma, power_mw) = status.readings();
+    let uptime = status.uptime_ms();
+    let now = crate::uptime_ms();
+    let format = if shell_status.json { "json" } else { "text" };
+
+    write!(response, "Firmware: ").ok();
+    version::write_version(response);
+    write!(response, "{}Uptime: {}.{:03} s", CR, uptime / 1000, uptime % 1000).ok();
+    match clock::wall_ms() {
+        Some(t) => {
+            let (year, month, day, hour, minute, second, _) = clock::to_utc(t);
+            write!(response, ", time {}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, hour, minute, second).ok()
+        },
+        None => write!(response, ", time not set").ok(),
+    };
+    write!(response, "{}Power: {}, storage: {}", CR, if status.is_powered() { "on" } else { "off" }, storage_name).ok();
+    write!(response, "{}Pins (commanded/sensed):", CR).ok();
+    for (i, name) in PIN_NAMES.iter().enumerate() {
+        write!(response, " {} {}/{}", name, pin_state(i), if pin_sensed(i) { "high" } else { "low" }).ok();
+    }
+    write!(response, "{}Meter: {:.3}V {:.3}A {:.3}W", CR,
+           voltage_mv as f32 / 1000.0, current_ma as f32 / 1000.0, power_mw as f32 / 1000.0).ok();
+    write!(response, "{}Console: {}, exit with CTRL+B 5 times, send escapes {}", CR,
+           if shell_status.console_mode { "on" } else { "off" }, if shell_status.console_mode { "off" } else { "on" }).ok();
+    write!(response, "{}Monitor: {}, Meter: {}, Format: {}", CR, shell_status.monitor_enabled, shell_status.meter_enabled, format).ok();
+    match lease.holder(now) {
+        Some(token) => write!(response, "{}Lease: held by \"{}\", {} s left", CR,
+                              str::from_utf8(token).unwrap_or("?"), lease.remaining_ms(now) / 1000).ok(),
+        None => write!(response, "{}Lease: free", CR).ok(),
+    };
+    write!(response, "{}Faults:", CR).ok();
+    if status.faults() == 0 {
+        write!(response, " none").ok();
+    }
+    for (_, name) in FAULT_NAMES.iter().filter(|(flag, _)| status.faults() & flag != 0) {
+        write!(response, " {}", name).ok();
+    }
+
+    // data: "monitor", "meter", "format", and "lease" with the "holder" token or null and
+    // the "remaining_ms". Then the values of the Status control request: "version",
+    // "git_ref", "uptime_ms", "time_ms" or null, "power", "storage" off, host or dut, "pins"
+    // with the "name", commanded "state" and "sensed" level of each pin, "voltage_v",
+    // "current_a", "power_w", "console" with "mode", "escape" and "send_escapes", and
+    // "faults", the FAULT_* flags of status.rs, also listed by name in "fault_names"
+    response.field("monitor", Value::Bool(shell_status.monitor_enabled));
+    response.field("meter", Value::Bool(shell_status.meter_enabled));
+    response.field("format", Value::Str(format));
+    response.open(Some("lease"), '{');
+    response.field("holder", lease.holder(now).map_or(Value::Null, Value::Bytes));
+    response.field("remaining_ms", Value::Int(lease.remaining_ms(now) as i64));
+    response.close('}');
+    response.field("version", Value::Str(version::version()));
+    response.field("git_ref", Value::Str(version::git_ref()));
+    response.field("uptime_ms", Value::Int(uptime as i64));
+    response.field("time_ms", clock::wall_ms().map_or(Value::Null, |t| Value::Int(t as i64)));
+    response.field("power", Value::Bool(status.is_powered()));
+    response.field("storage", Value::Str(storage_name));
+    response.open(Some("pins"), '[');
+    for (i, name) in PIN_NAMES.iter().enumerate() {
+        response.open(None, '{');
+        response.field("name", Value::Str(name));
+        response.field("state", Value::Str(pin_state(i)));
+        response.field("sensed", Value::Bool(pin_sensed(i)));
+        response.close('}');
+    }
+    response.close(']');
+    response.field("voltage_v", Value::Float(voltage_mv as f32 / 1000.0));
+    response.field("current_a", Value::Float(current_ma as f32 / 1000.0));
+    response.field("power_w", Value::Float(power_mw as f32 / 1000.0));
+    response.open(Some("console"), '{');
+    response.field("mode", Value::Bool(shell_status.console_mode));
+    response.field("escape", Value::Str("CTRL+B x5"));
+    response.field("send_escapes", Value::Bool(!shell_status.console_mode));
+    response.close('}');
+    response.field("faults", Value::Int(status.faults() as i64));
+    response.open(Some("fault_names"), '[');
+    for (_, name) in FAULT_NAMES.iter().filter(|(flag, _)| status.faults() & flag != 0) {
+        response.element(Value::Str(name));
     }
+    response.close(']');
 }
\ No newline at end of file
@@ -0,0 +1,148 @@
+use crate::config::{as_u8_slice, ConfigStats};
+use crate::ctlpins::{CTLPinsTrait, PinState};
+use crate::powermeter::{PowerReadings, OVERCURRENT_LIMIT_A};
+use crate::storage::{StorageState, StorageSwitchTrait};
+
+// Binary snapshot of the device state for the control interface, so host clients can poll
+// everything with a single request instead of parsing strings. The struct is sent as is,
+// all the values are little endian. New fields must be added at the end, bumping
+// STATUS_VERSION, so older clients can keep parsing the fields they know about.
+
+pub const STATUS_VERSION: u8 = 2;
+
+pub const FAULT_OVERCURRENT: u32 = 1 << 0;    // current above OVERCURRENT_LIMIT_A
+pub const FAULT_PIN_MISMATCH: u32 = 1 << 1;   // a driven CTL pin reads back a different level
+pub const FAULT_CONFIG_WRITE: u32 = 1 << 2;   // the last config operation over USB or of the button failed
+pub const FAULT_CONFIG_WEAR: u32 = 1 << 3;    // the config sector is close to its erase limit
+
+pub const FAULT_NAMES: [(u32, &str); 4] = [
+    (FAULT_OVERCURRENT, "overcurrent"),
+    (FAULT_PIN_MISMATCH, "pin mismatch"),
+    (FAULT_CONFIG_WRITE, "config write"),
+    (FAULT_CONFIG_WEAR, "config wear"),
+];
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct DeviceStatus {
+    version: u8,        // STATUS_VERSION
+    length: u8,         // size of the struct in bytes
+    power: u8,          // 0 off, 1 on
+    storage: u8,        // 0 off, 1 host, 2 DUT, as in StorageAction
+    pins: [u8; 5],      // commanded reset, a, b, c and d: 0 low, 1 high, 2 floating, as in SetPinState
+    sensed: u8,         // level read back from the pins, bit 0 reset, bits 1-4 a-d
+    voltage_mv: i32,
+    current_ma: i32,
+    power_mw: i32,
+    uptime_ms: u64,
+    faults: u32,        // FAULT_* flags
+    time_ms: u64,       // epoch time in ms, 0 until the host sets it, since version 2
+}
+
+impl DeviceStatus {
+    pub fn new() -> Self {
+        DeviceStatus {
+            version: STATUS_VERSION,
+            length: core::mem::size_of::<DeviceStatus>() as u8,
+            power: 0,
+            storage: 0,
+            pins: [2; 5],
+            sensed: 0,
+            voltage_mv: 0,
+            current_ma: 0,
+            power_mw: 0,
+            uptime_ms: 0,
+            faults: 0,
+            time_ms: 0,
+        }
+    }
+
+    pub fn capture<C: CTLPinsTrait, S: StorageSwitchTrait>(
+        ctlpins: &C,
+        storage: &S,
+        readings: &PowerReadings,
+        stats: &ConfigStats,
+    ) -> Self {
+        let mut status = DeviceStatus::new();
+        let on = ctlpins.is_on();
+        status.power = on as u8;
+        status.storage = match storage.get_state() {
+            StorageState::Off => 0,
+            StorageState::Host => 1,
+            StorageState::DUT => 2,
+        };
+
+        let pins = ctlpins.get_pins();
+        let sensed = ctlpins.sense_pins();
+        let mut faults = 0;
+        for (i, (state, high)) in pins.iter().zip(sensed.iter()).enumerate() {
+            status.pins[i] = match state {
+                PinState::Low => 0,
+                PinState::High => 1,
+                PinState::Floating => 2,
+            };
+            status.sensed |= (*high as u8) << i;
+            // high outputs are left floating while the DUT is off, see ctlpins::off_tolerant
+            let mismatch = match state {
+                PinState::Low => *high,
+                PinState::High => on && !*high,
+                PinState::Floating => false,
+            };
+            if mismatch {
+                faults |= FAULT_PIN_MISMATCH;
+            }
+        }
+
+        status.voltage_mv = (readings.voltage * 1000.0) as i32;
+        status.current_ma = (readings.current * 1000.0) as i32;
+        status.power_mw = (readings.power * 1000.0) as i32;
+        if readings.current > OVERCURRENT_LIMIT_A {
+            faults |= FAULT_OVERCURRENT;
+        }
+        if stats.wear_warning() {
+            faults |= FAULT_CONFIG_WEAR;
+        }
+
+        status.uptime_ms = crate::uptime_ms();
+        status.time_ms = crate::clock::wall_ms().unwrap_or(0);
+        status.faults = faults;
+        status
+    }
+
+    pub fn set_fault(&mut self, fault: u32) {
+        self.faults |= fault;
+    }
+
+    pub fn faults(&self) -> u32 {
+        self.faults
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.power != 0
+    }
+
+    pub fn storage(&self) -> u8 {
+        self.storage
+    }
+
+    pub fn pins(&self) -> [u8; 5] {
+        self.pins
+    }
+
+    pub fn sensed(&self) -> u8 {
+        self.sensed
+    }
+
+    // voltage in mV, current in mA and power in mW
+    pub fn readings(&self) -> (i32, i32, i32) {
+        (self.voltage_mv, self.current_ma, self.power_mw)
+    }
+
+    pub fn uptime_ms(&self) -> u64 {
+        self.uptime_ms
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { as_u8_slice(self) }
+    }
+}
@@ -3,10 +3,18 @@ use embedded_hal::digital::OutputPin;
 
  // Device control abstractions
 
+ #[derive(Copy, Clone, PartialEq)]
+ pub enum StorageState {
+     Off,
+     Host,
+     DUT,
+ }
+
  pub trait StorageSwitchTrait {
      fn power_off(&mut self);
      fn connect_to_dut(&mut self);
      fn connect_to_host(&mut self);
+     fn get_state(&self) -> StorageState;
  }
  pub struct StorageSwitch<OEnPin, SelPin, PwDUTPin, PWHostPin>
  where
@@ -19,6 +27,7 @@ use embedded_hal::digital::OutputPin;
      usb_store_sel: SelPin,
      usb_pw_dut: PwDUTPin,
      usb_pw_host: PWHostPin,
+     state: StorageState,
  }
 
  impl<OEnPin, SelPin, PwDUTPin, PWHostPin> StorageSwitch<OEnPin, SelPin, PwDUTPin, PWHostPin>
@@ -39,6 +48,7 @@ use embedded_hal::digital::OutputPin;
              usb_store_sel,
              usb_pw_dut,
              usb_pw_host,
+             state: StorageState::Off,
          }
      }
 }
@@ -55,6 +65,7 @@ use embedded_hal::digital::OutputPin;
         self.usb_pw_dut.set_low().ok();
         self.usb_pw_host.set_low().ok();
         self.usb_store_oen.set_high().ok();
+        self.state = StorageState::Off;
      }
 
      fn connect_to_dut(&mut self) {
@@ -62,6 +73,7 @@ use embedded_hal::digital::OutputPin;
          self.usb_pw_dut.set_high().ok();
          self.usb_store_oen.set_low().ok();
          self.usb_store_sel.set_high().ok();
+         self.state = StorageState::DUT;
      }
 
      fn connect_to_host(&mut self) {
@@ -69,5 +81,10 @@ use embedded_hal::digital::OutputPin;
          self.usb_pw_host.set_high().ok();
          self.usb_store_oen.set_low().ok();
          self.usb_store_sel.set_low().ok();
+         self.state = StorageState::Host;
+     }
+
+     fn get_state(&self) -> StorageState {
+         self.state
      }
  }
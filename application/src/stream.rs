@@ -0,0 +1,271 @@
+use heapless::Deque;
+
+use crate::clock::{Timestamp, TIMESTAMP_SIZE};
+use crate::powermeter::{current_from_raw, voltage_from_raw};
+
+// Power samples streamed over the interrupt IN endpoint of the control interface.
+//
+// The ADC task pushes every conversion into the stream buffer while streaming is enabled,
+// and the USB task packs them into packets of up to 60 bytes. When the host doesn't read fast enough
+// the newest samples are dropped and counted, the count is reported in the next packet.
+//
+// packet format, all values little endian:
+//   0  u8  kind: 0 raw samples, 1 converted samples, events use 2, see events.rs
+//   1  u8  number of samples in the packet
+//   2  u16 samples dropped since the previous packet, saturating
+//   4  u32 index of the first sample since the stream was started
+//   8  Timestamp of the first sample, epoch time or uptime, see clock.rs
+//   17 samples, 4 bytes each:
+//        raw:       current ADC counts (u16), voltage ADC counts (u16)
+//        converted: voltage in mV (u16), current in mA (i16)
+//
+// consecutive samples are 1/rate seconds apart, unless samples were dropped in between
+//
+// The ADC runs at the stream rate, or at the smallest multiple of it reaching DEFAULT_RATE_HZ
+// for the lower rates, as the power meter keeps taking DEFAULT_RATE_HZ samples per second.
+// The stream then takes every nth conversion.
+
+pub const PACKET_SIZE: usize = 64;
+const HEADER_SIZE: usize = 8 + TIMESTAMP_SIZE;
+const SAMPLE_SIZE: usize = 4;
+// one sample less than what fits, so every packet is a short packet that
+// completes the host transfer whatever the size of its read buffer
+const SAMPLES_PER_PACKET: usize = (PACKET_SIZE - HEADER_SIZE) / SAMPLE_SIZE - 1;
+const BUFFER_SAMPLES: usize = 256;
+
+pub const DEFAULT_RATE_HZ: u16 = 100;
+pub const MIN_RATE_HZ: u16 = 1;
+pub const MAX_RATE_HZ: u16 = 1000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+    Raw,
+    Converted,
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    current_raw: u16,
+    voltage_raw: u16,
+    index: u32,
+    timestamp: Timestamp,
+}
+
+pub struct SampleStream {
+    samples: Deque<Sample, BUFFER_SAMPLES>,
+    format: Option<StreamFormat>, // None while stopped
+    rate_hz: u16,
+    decimator: Decimator, // picks the samples of the stream out of the ADC conversions
+    index: u32,         // index of the next sample taken, dropped ones included
+    dropped: u32,       // dropped since the last packet
+    total_dropped: u32, // dropped since the stream was started
+}
+
+impl SampleStream {
+    pub fn new() -> Self {
+        SampleStream {
+            samples: Deque::new(),
+            format: None,
+            rate_hz: DEFAULT_RATE_HZ,
+            decimator: Decimator::new(),
+            index: 0,
+            dropped: 0,
+            total_dropped: 0,
+        }
+    }
+
+    pub fn start(&mut self, format: StreamFormat, rate_hz: u16) {
+        self.samples.clear();
+        self.format = Some(format);
+        self.rate_hz = rate_hz.clamp(MIN_RATE_HZ, MAX_RATE_HZ);
+        self.decimator = Decimator::new();
+        self.index = 0;
+        self.dropped = 0;
+        self.total_dropped = 0;
+    }
+
+    pub fn stop(&mut self) {
+        self.samples.clear();
+        self.format = None;
+        self.rate_hz = DEFAULT_RATE_HZ;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.format.is_some()
+    }
+
+    // sampling rate of the stream, DEFAULT_RATE_HZ when not streaming
+    pub fn rate_hz(&self) -> u16 {
+        self.rate_hz
+    }
+
+    // ADC conversion rate, the smallest multiple of the stream rate reaching DEFAULT_RATE_HZ
+    pub fn adc_rate_hz(&self) -> u16 {
+        DEFAULT_RATE_HZ.div_ceil(self.rate_hz) * self.rate_hz
+    }
+
+    // called for every ADC conversion
+    pub fn push(&mut self, current_raw: u16, voltage_raw: u16, timestamp: Timestamp) {
+        if !self.is_enabled() || !self.decimator.take(self.rate_hz, self.adc_rate_hz()) {
+            return;
+        }
+        let sample = Sample { current_raw, voltage_raw, index: self.index, timestamp };
+        self.index = self.index.wrapping_add(1);
+        if self.samples.push_back(sample).is_err() {
+            self.dropped = self.dropped.saturating_add(1);
+            self.total_dropped = self.total_dropped.saturating_add(1);
+        }
+    }
+
+    // build the next packet without consuming the samples, see consume
+    pub fn next_packet(&self) -> Option<heapless::Vec<u8, PACKET_SIZE>> {
+        let format = self.format?;
+        let first = self.samples.front()?;
+        let count = self.samples.len().min(SAMPLES_PER_PACKET);
+
+        let mut buf = heapless::Vec::<u8, PACKET_SIZE>::new();
+        buf.push(match format {
+            StreamFormat::Raw => 0,
+            StreamFormat::Converted => 1,
+        }).ok();
+        buf.push(count as u8).ok();
+        buf.extend_from_slice(&(self.dropped.min(u16::MAX as u32) as u16).to_le_bytes()).ok();
+        buf.extend_from_slice(&first.index.to_le_bytes()).ok();
+        buf.extend_from_slice(&first.timestamp.to_bytes()).ok();
+        for s in self.samples.iter().take(count) {
+            match format {
+                StreamFormat::Raw => {
+                    buf.extend_from_slice(&s.current_raw.to_le_bytes()).ok();
+                    buf.extend_from_slice(&s.voltage_raw.to_le_bytes()).ok();
+                }
+                StreamFormat::Converted => {
+                    let mv = (voltage_from_raw(s.voltage_raw) * 1000.0) as u16;
+                    let ma = (current_from_raw(s.current_raw) * 1000.0) as i16;
+                    buf.extend_from_slice(&mv.to_le_bytes()).ok();
+                    buf.extend_from_slice(&ma.to_le_bytes()).ok();
+                }
+            }
+        }
+        Some(buf)
+    }
+
+    // drop the samples of a packet once the endpoint accepted it
+    pub fn consume(&mut self, count: usize) {
+        for _ in 0..count {
+            self.samples.pop_front();
+        }
+        self.dropped = 0;
+    }
+
+    // stream status for the control interface, all values little endian:
+    //   0 u8  0 stopped, 1 raw samples, 2 converted samples
+    //   1 u16 sampling rate in Hz
+    //   3 u32 samples dropped since the stream was started
+    //   7 u16 samples waiting to be sent
+    pub fn status(&self) -> heapless::Vec<u8, 9> {
+        let mut buf = heapless::Vec::<u8, 9>::new();
+        buf.push(match self.format {
+            None => 0,
+            Some(StreamFormat::Raw) => 1,
+            Some(StreamFormat::Converted) => 2,
+        }).ok();
+        buf.extend_from_slice(&self.rate_hz.to_le_bytes()).ok();
+        buf.extend_from_slice(&self.total_dropped.to_le_bytes()).ok();
+        buf.extend_from_slice(&(self.samples.len() as u16).to_le_bytes()).ok();
+        buf
+    }
+}
+
+// Takes rate_hz out of every input_hz samples, spread as evenly as the ratio allows: every
+// nth sample when input_hz is a multiple of rate_hz
+pub struct Decimator {
+    accumulator: u32,
+}
+
+impl Decimator {
+    pub const fn new() -> Self {
+        Decimator { accumulator: 0 }
+    }
+
+    // true when the current sample is one of those taken
+    pub fn take(&mut self, rate_hz: u16, input_hz: u16) -> bool {
+        let input_hz = input_hz.max(1) as u32;
+        self.accumulator += rate_hz as u32;
+        if self.accumulator < input_hz {
+            return false;
+        }
+        // the modulo keeps the accumulator in range after a rate change
+        self.accumulator = (self.accumulator - input_hz) % input_hz;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn taken(rate_hz: u16, input_hz: u16, samples: usize) -> Vec<usize> {
+        let mut decimator = Decimator::new();
+        (0..samples).filter(|_| decimator.take(rate_hz, input_hz)).collect()
+    }
+
+    #[test]
+    fn adc_rate_is_a_multiple_of_the_stream_rate() {
+        let mut stream = SampleStream::new();
+        assert_eq!(stream.adc_rate_hz(), DEFAULT_RATE_HZ);
+        for (rate, adc_rate) in [(1, 100), (7, 105), (30, 120), (100, 100), (150, 150), (1000, 1000)].iter() {
+            stream.start(StreamFormat::Raw, *rate);
+            assert_eq!(stream.adc_rate_hz(), *adc_rate);
+            assert!(stream.adc_rate_hz() >= DEFAULT_RATE_HZ);
+        }
+        stream.stop();
+        assert_eq!(stream.adc_rate_hz(), DEFAULT_RATE_HZ);
+    }
+
+    #[test]
+    fn power_meter_rate() {
+        // one second of conversions at any ADC rate feeds DEFAULT_RATE_HZ samples
+        for adc_rate in [100u16, 105, 120, 150, 999, 1000].iter() {
+            assert_eq!(taken(DEFAULT_RATE_HZ, *adc_rate, *adc_rate as usize * 3).len(), 3 * DEFAULT_RATE_HZ as usize);
+        }
+    }
+
+    #[test]
+    fn stream_takes_every_nth_conversion() {
+        let taken = taken(30, 120, 20);
+        assert_eq!(taken, [3, 7, 11, 15, 19]);
+    }
+
+    #[test]
+    fn stream_samples() {
+        let mut stream = SampleStream::new();
+        stream.start(StreamFormat::Raw, 30);
+        for i in 0..120 {
+            stream.push(i, 0, Timestamp { wall: false, ms: i as u64 });
+        }
+        assert_eq!(stream.samples.len(), 30);
+        assert!(stream.samples.iter().all(|s| s.current_raw % 4 == 3));
+    }
+
+    #[test]
+    fn packet_encoding() {
+        let mut stream = SampleStream::new();
+        stream.start(StreamFormat::Raw, DEFAULT_RATE_HZ);
+        for i in 0..SAMPLES_PER_PACKET as u16 + 2 {
+            stream.push(i, 1000 + i, Timestamp { wall: true, ms: 1_700_000_000_000 + 10 * i as u64 });
+        }
+        let packet = stream.next_packet().unwrap();
+        assert_eq!(packet.len(), HEADER_SIZE + SAMPLES_PER_PACKET * SAMPLE_SIZE);
+        assert!(packet.len() < PACKET_SIZE);
+        assert_eq!(&packet[..8], [0, SAMPLES_PER_PACKET as u8, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&packet[8..HEADER_SIZE], Timestamp { wall: true, ms: 1_700_000_000_000 }.to_bytes());
+        assert_eq!(&packet[HEADER_SIZE..HEADER_SIZE + 8], [0, 0, 0xe8, 0x03, 1, 0, 0xe9, 0x03]);
+
+        stream.consume(packet[1] as usize);
+        let packet = stream.next_packet().unwrap();
+        assert_eq!(packet[1], 2);
+        assert_eq!(packet[4], SAMPLES_PER_PACKET as u8);
+        let ms = 1_700_000_000_000 + 10 * SAMPLES_PER_PACKET as u64;
+        assert_eq!(&packet[8..HEADER_SIZE], Timestamp { wall: true, ms }.to_bytes());
+    }
+}
@@ -0,0 +1,171 @@
+use arrayvec::ArrayString;
+
+// Shared argument parsing for the shell commands taking values:
+//   words are separated by whitespace, a "double quoted" part of a word can contain spaces,
+//   and a backslash escapes a double quote, a backslash or a space. Any other backslash
+//   sequence is kept as is, so escapes for the DUT (\r, \n, \w ...) reach the send queue.
+//   remainder() takes the rest of the line with the same quoting and escapes, keeping the
+//   whitespace between its words, raw_remainder() takes it verbatim.
+pub struct Tokenizer<'a> {
+    rest: &'a str,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    UnterminatedQuote,
+    TooLong,
+}
+
+impl TokenError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenError::UnterminatedQuote => "unterminated quote",
+            TokenError::TooLong => "argument too long",
+        }
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(args: &'a str) -> Self {
+        Self { rest: args }
+    }
+
+    pub fn next_token<const N: usize>(&mut self) -> Result<Option<ArrayString<N>>, TokenError> {
+        self.rest = self.rest.trim_start();
+        if self.rest.is_empty() {
+            return Ok(None);
+        }
+        let (token, end) = unescape(self.rest, false)?;
+        self.rest = &self.rest[end..];
+        Ok(Some(token))
+    }
+
+    pub fn remainder<const N: usize>(&mut self) -> Result<ArrayString<N>, TokenError> {
+        let rest = self.rest.trim();
+        self.rest = "";
+        unescape(rest, true).map(|(text, _)| text)
+    }
+
+    pub fn raw_remainder(&mut self) -> &'a str {
+        let rest = self.rest.trim();
+        self.rest = "";
+        rest
+    }
+}
+
+// remove the quotes and escapes of s up to the first whitespace outside quotes, or up to its
+// end when whole, returns the text and the offset where it stopped
+fn unescape<const N: usize>(s: &str, whole: bool) -> Result<(ArrayString<N>, usize), TokenError> {
+    let mut text = ArrayString::<N>::new();
+    let mut push = |c: char| text.try_push(c).map_err(|_| TokenError::TooLong);
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut end = s.len();
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            if c != '"' && c != '\\' && c != ' ' {
+                push('\\')?;
+            }
+            push(c)?;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c.is_whitespace() && !quoted && !whole {
+            end = i;
+            break;
+        } else {
+            push(c)?;
+        }
+    }
+    if escaped {
+        push('\\')?;
+    }
+    if quoted {
+        return Err(TokenError::UnterminatedQuote);
+    }
+    Ok((text, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(args: &str) -> Result<Vec<String>, TokenError> {
+        let mut tokenizer = Tokenizer::new(args);
+        let mut tokens = Vec::new();
+        while let Some(token) = tokenizer.next_token::<32>()? {
+            tokens.push(token.to_string());
+        }
+        Ok(tokens)
+    }
+
+    fn remainder(args: &str) -> Result<String, TokenError> {
+        Tokenizer::new(args).remainder::<32>().map(|r| r.to_string())
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(tokens("").unwrap(), Vec::<String>::new());
+        assert_eq!(tokens("   ").unwrap(), Vec::<String>::new());
+        assert_eq!(tokens(" name  dut-1\ttags ").unwrap(), ["name", "dut-1", "tags"]);
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(tokens(r#"name "my board" x"#).unwrap(), ["name", "my board", "x"]);
+        assert_eq!(tokens(r#"a"b c"d"#).unwrap(), ["ab cd"]);
+        assert_eq!(tokens(r#""""#).unwrap(), [""]);
+        assert_eq!(tokens(r#"name "my board"#), Err(TokenError::UnterminatedQuote));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(tokens(r#"foo\ bar"#).unwrap(), ["foo bar"]);
+        assert_eq!(tokens(r#"a\"b "c\"d""#).unwrap(), [r#"a"b"#, r#"c"d"#]);
+        assert_eq!(tokens(r#"a\\b"#).unwrap(), [r"a\b"]);
+        // escapes for the DUT are kept, as is a trailing backslash
+        assert_eq!(tokens(r#"a\r\n b\"#).unwrap(), [r"a\r\n", r"b\"]);
+    }
+
+    #[test]
+    fn too_long() {
+        assert_eq!(tokens(&"x".repeat(33)), Err(TokenError::TooLong));
+        assert_eq!(tokens(&"x".repeat(32)).unwrap(), ["x".repeat(32)]);
+        assert_eq!(remainder(&"x ".repeat(17)), Err(TokenError::TooLong));
+    }
+
+    #[test]
+    fn remainder_unescapes() {
+        assert_eq!(remainder(r#"foo\ bar"#).unwrap(), "foo bar");
+        assert_eq!(remainder(r#"a\"b"#).unwrap(), r#"a"b"#);
+        assert_eq!(remainder(r#""a b""#).unwrap(), "a b");
+        assert_eq!(remainder(r#"  "a  b" c\\d  e\r "#).unwrap(), r"a  b c\d  e\r");
+        assert_eq!(remainder(r#"a "b"#), Err(TokenError::UnterminatedQuote));
+        assert_eq!(remainder("").unwrap(), "");
+    }
+
+    #[test]
+    fn remainder_after_tokens() {
+        let mut tokenizer = Tokenizer::new(r#"-s secret name my\ board  "rev 2""#);
+        assert_eq!(tokenizer.next_token::<8>().unwrap().unwrap().as_str(), "-s");
+        assert_eq!(tokenizer.next_token::<8>().unwrap().unwrap().as_str(), "secret");
+        assert_eq!(tokenizer.next_token::<8>().unwrap().unwrap().as_str(), "name");
+        assert_eq!(tokenizer.remainder::<32>().unwrap().as_str(), "my board  rev 2");
+        assert_eq!(tokenizer.next_token::<8>().unwrap(), None);
+    }
+
+    #[test]
+    fn raw_remainder_keeps_json() {
+        // set-config json {"a":[1,"x"]}, after the command name
+        let mut tokenizer = Tokenizer::new(r#" json  {"a":[1,"x"]} "#);
+        assert_eq!(tokenizer.next_token::<16>().unwrap().unwrap().as_str(), "json");
+        let json = tokenizer.raw_remainder();
+        assert_eq!(json, r#"{"a":[1,"x"]}"#);
+        assert_eq!(crate::json::validate(json.as_bytes()), Ok(()));
+        assert_eq!(crate::json::query(json.as_bytes(), "a.1"), Ok(Some(&br#""x""#[..])));
+        assert_eq!(tokenizer.raw_remainder(), "");
+    }
+}
@@ -2,6 +2,7 @@
 use core::fmt::Write;
 const VERSION: &str = env!("VERSION");
 const GIT_REF: &str  = env!("GIT_REF");
+const HW_REVISION: &str = env!("HW_REVISION");
 
 pub const fn version() -> &'static str {
     VERSION
@@ -11,6 +12,17 @@ pub const fn git_ref() -> &'static str {
     GIT_REF
 }
 
+// revision of the dutlink-board this firmware is built for
+pub const fn hardware_revision() -> u8 {
+    let mut revision: u8 = 0;
+    let mut bytes = HW_REVISION.as_bytes();
+    while let [byte, rest @ ..] = bytes {
+        bytes = rest;
+        revision = revision * 10 + (*byte - b'0');
+    }
+    revision
+}
+
 pub fn write_version(writer: &mut dyn Write) {
     write!(writer, "{} git-ref: {}", version(), git_ref()).ok();
 }